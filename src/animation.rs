@@ -0,0 +1,292 @@
+use crate::character::{Character, CharacterDef};
+use crate::hitstop::Hitstop;
+use crate::player::{Direction, Player};
+use crate::player_state::{react_to_blocks, CurrentMove, PlayerState};
+use crate::rollback::{Resimulating, RollbackAppExt};
+use crate::simulation::GameSet;
+use bevy::utils::thiserror;
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    reflect::TypePath,
+    utils::BoxedFuture,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use thiserror::Error;
+
+const ANIMATIONS_PATH: &str = "player.anim.ron";
+
+/// One cell of the sprite sheet and how many simulation ticks it is shown for.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AnimationFrame {
+    pub index: usize,
+    pub duration: u32,
+    /// Markers sent as `AnimationEvent`s when this frame comes up.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AnimationClip {
+    pub frames: Vec<AnimationFrame>,
+    /// One-shot clips hold their last frame once they are done.
+    #[serde(default)]
+    pub looping: bool,
+}
+
+#[derive(Deserialize)]
+struct AnimationSetFile {
+    sheet: String,
+    tile_size: Vec2,
+    columns: usize,
+    rows: usize,
+    animations: HashMap<String, AnimationClip>,
+}
+
+/// Every animation of a fighter, cut from a single sprite sheet.
+///
+/// Loaded from `*.anim.ron` files; the atlas is built from the sheet the file names, and
+/// both are reloaded when the file changes.
+#[derive(Asset, TypePath, Clone, Debug)]
+pub struct AnimationSet {
+    pub atlas: Handle<TextureAtlas>,
+    pub animations: HashMap<String, AnimationClip>,
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum AnimationSetLoaderError {
+    #[error("could not read animations: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse animations: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+    #[error("animation {0:?} has no frames")]
+    NoFrames(String),
+    #[error("animation {0:?} uses cell {1}, but the sheet only has {2}")]
+    FrameIndex(String, usize, usize),
+}
+
+#[derive(Default)]
+pub struct AnimationSetLoader;
+
+impl AssetLoader for AnimationSetLoader {
+    type Asset = AnimationSet;
+    type Settings = ();
+    type Error = AnimationSetLoaderError;
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            let file = ron::de::from_bytes::<AnimationSetFile>(&bytes)?;
+            let cells = file.columns * file.rows;
+            for (name, clip) in file.animations.iter() {
+                if clip.frames.is_empty() {
+                    return Err(AnimationSetLoaderError::NoFrames(name.clone()));
+                }
+                if let Some(frame) = clip.frames.iter().find(|frame| frame.index >= cells) {
+                    return Err(AnimationSetLoaderError::FrameIndex(
+                        name.clone(),
+                        frame.index,
+                        cells,
+                    ));
+                }
+            }
+            let sheet = load_context.load(file.sheet);
+            let atlas =
+                TextureAtlas::from_grid(sheet, file.tile_size, file.columns, file.rows, None, None);
+            Ok(AnimationSet {
+                atlas: load_context.add_labeled_asset(String::from("atlas"), atlas),
+                animations: file.animations,
+            })
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["anim.ron"]
+    }
+}
+
+/// Plays a clip from `set`, advanced once per simulation tick.
+#[derive(Component, Clone, Debug)]
+pub struct Animator {
+    pub set: Handle<AnimationSet>,
+    pub animation: String,
+    pub frame: usize,
+    /// Ticks the current frame has been shown.
+    pub elapsed: u32,
+}
+
+impl Animator {
+    pub fn new(set: Handle<AnimationSet>) -> Self {
+        Self {
+            set,
+            animation: String::new(),
+            frame: 0,
+            elapsed: 0,
+        }
+    }
+}
+
+/// A marker on an animation frame was reached.
+#[derive(Event, Clone, Debug)]
+pub struct AnimationEvent {
+    pub entity: Entity,
+    pub animation: String,
+    pub marker: String,
+}
+
+/// The set given to players that do not bring their own.
+#[derive(Resource)]
+pub struct DefaultAnimations(pub Handle<AnimationSet>);
+
+pub struct AnimationPlugin;
+
+impl Plugin for AnimationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<AnimationSet>()
+            .init_asset_loader::<AnimationSetLoader>()
+            .add_event::<AnimationEvent>()
+            .rollback_component::<Animator>()
+            .add_systems(Startup, load_animations)
+            .add_systems(Update, attach_animator)
+            .add_systems(
+                FixedUpdate,
+                animate.after(react_to_blocks).in_set(GameSet::Combat),
+            );
+    }
+}
+
+fn load_animations(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(DefaultAnimations(asset_server.load(ANIMATIONS_PATH)));
+}
+
+/// Players still showing the level's static sprite.
+type WithoutAnimator = (With<Player>, Without<Animator>);
+
+/// Swaps the level's static sprite for the character's animated sheet once the set has
+/// loaded.
+fn attach_animator(
+    mut commands: Commands,
+    default_animations: Res<DefaultAnimations>,
+    animation_sets: Res<Assets<AnimationSet>>,
+    characters: Res<Assets<CharacterDef>>,
+    query: Query<(Entity, Option<&Character>), WithoutAnimator>,
+) {
+    for (entity, character) in query.iter() {
+        let handle = character
+            .and_then(|character| characters.get(&character.def))
+            .map_or(&default_animations.0, |def| &def.animations);
+        let Some(set) = animation_sets.get(handle) else {
+            continue;
+        };
+        commands
+            .entity(entity)
+            .remove::<(Sprite, Handle<Image>)>()
+            .insert((
+                TextureAtlasSprite::new(0),
+                set.atlas.clone(),
+                Animator::new(handle.clone()),
+            ));
+    }
+}
+
+/// The clip for a player's state. Attacks use a clip named after the move when there is
+/// one.
+fn animation_for<'a>(
+    state: PlayerState,
+    current_move: &'a CurrentMove,
+    set: &AnimationSet,
+) -> &'a str {
+    match state {
+        PlayerState::Idle => "idle",
+        PlayerState::Walk => "walk",
+        PlayerState::Jump => "jump",
+        PlayerState::Fall => "fall",
+        PlayerState::Crouch => "crouch",
+        PlayerState::Attack if set.animations.contains_key(&current_move.name) => {
+            &current_move.name
+        }
+        PlayerState::Attack => "attack",
+        PlayerState::Hitstun | PlayerState::Juggle => "hit",
+        PlayerState::Blockstun => "block",
+        PlayerState::Knockdown => "ko",
+    }
+}
+
+/// The sprite of a player and the state its clip is picked from.
+type AnimatedPlayer<'a> = (
+    Entity,
+    &'a mut Animator,
+    &'a mut TextureAtlasSprite,
+    &'a mut Handle<TextureAtlas>,
+    &'a PlayerState,
+    &'a CurrentMove,
+    &'a Direction,
+    &'a Hitstop,
+);
+
+/// Picks each player's clip from their state, advances it and shows the current cell,
+/// flipped to face the player's direction. Frozen players hold their frame. Markers are
+/// only sent the first time a frame is simulated, not again after a rollback.
+fn animate(
+    animation_sets: Res<Assets<AnimationSet>>,
+    resimulating: Res<Resimulating>,
+    mut query: Query<AnimatedPlayer, With<Player>>,
+    mut ev_animation: EventWriter<AnimationEvent>,
+) {
+    for (entity, mut animator, mut sprite, mut atlas, state, current_move, direction, hitstop) in
+        query.iter_mut()
+    {
+        let Some(set) = animation_sets.get(&animator.set) else {
+            continue;
+        };
+        // a hot reload builds a new atlas
+        if *atlas != set.atlas {
+            *atlas = set.atlas.clone();
+        }
+        sprite.flip_x = matches!(direction, Direction::Left);
+
+        let name = animation_for(*state, current_move, set);
+        let Some(clip) = set.animations.get(name) else {
+            continue;
+        };
+        // the clip may have shrunk in a hot reload
+        animator.frame = animator.frame.min(clip.frames.len() - 1);
+        let entered = if animator.animation != name {
+            animator.animation = name.to_string();
+            animator.frame = 0;
+            animator.elapsed = 0;
+            true
+        } else if hitstop.frozen() {
+            false
+        } else {
+            animator.elapsed += 1;
+            let last = animator.frame + 1 == clip.frames.len();
+            if animator.elapsed < clip.frames[animator.frame].duration || (last && !clip.looping) {
+                false
+            } else {
+                animator.elapsed = 0;
+                animator.frame = if last { 0 } else { animator.frame + 1 };
+                true
+            }
+        };
+
+        let frame = &clip.frames[animator.frame];
+        sprite.index = frame.index;
+        if entered && !resimulating.0 {
+            for marker in frame.events.iter() {
+                ev_animation.send(AnimationEvent {
+                    entity,
+                    animation: animator.animation.clone(),
+                    marker: marker.clone(),
+                });
+            }
+        }
+    }
+}
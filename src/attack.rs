@@ -1,143 +1,352 @@
-use crate::movement::{PlayerInput, PlayerInputEvent, Velocity};
-use crate::player::{AttackCooldown, AttackHeight, Direction, Player};
-use bevy::{prelude::*, sprite::MaterialMesh2dBundle};
+use crate::collision::{hitbox_groups, Hurtbox};
+use crate::moves::{GuardType, Hitbox, MoveDef, MoveList, MovePhase, Moveset};
+use crate::player::{Direction, Health, Player, PlayerId};
+use crate::player_state::{CurrentMove, Guard, PlayerState, StateTimer};
+use crate::rollback::{Resimulating, Rollback, RollbackAppExt};
+use crate::simulation::GameSet;
+use bevy::prelude::*;
 use bevy_rapier2d::prelude::*;
-#[derive(Component)]
+use std::collections::HashMap;
+#[derive(Component, Copy, Clone)]
 pub struct Attack;
-#[derive(Component)]
+/// Hit data copied from the move when its hitbox comes out.
+#[derive(Component, Clone)]
 pub struct AttackProperties {
     pub damage: f32,
-    pub range: f32,
+    pub chip_damage: f32,
+    pub guard: GuardType,
+    pub hitstun: u32,
+    pub blockstun: u32,
+    /// Already flipped to point away from the attacker.
+    pub knockback: Vec2,
+    pub hitstop: u32,
+    pub shake: f32,
+    /// Players already hit; a move connects at most once per victim.
+    pub victims: Vec<Entity>,
 }
 
+/// The player entity that spawned an attack. Attacks never hit their owner.
+#[derive(Component, Copy, Clone, Debug)]
+pub struct AttackOwner(pub Entity);
+
 #[derive(Bundle)]
 pub struct AttackBundle {
-    model: MaterialMesh2dBundle<ColorMaterial>,
-    timer: AttackDespawnTimer,
+    transform: TransformBundle,
     collider: Collider,
     sensor: Sensor,
+    active_events: ActiveEvents,
+    active_collision_types: ActiveCollisionTypes,
+    collision_groups: CollisionGroups,
     attack_properties: AttackProperties,
-    velocity: Velocity,
+    owner: AttackOwner,
+    rollback: Rollback,
 }
 
-#[derive(Component)]
-pub struct AttackDespawnTimer {
-    pub timer: Timer,
+#[derive(Event, Copy, Clone, Debug)]
+pub struct PlayerHitEvent {
+    pub attacker: Entity,
+    pub victim: Entity,
+    pub damage: f32,
+    pub hitstun: u32,
+    pub knockback: Vec2,
+    pub hitstop: u32,
+    /// Camera trauma to add, from 0 to 1.
+    pub shake: f32,
+}
+
+/// An attack that reached a defender who was guarding it correctly.
+#[derive(Event, Copy, Clone, Debug)]
+pub struct PlayerBlockEvent {
+    pub attacker: Entity,
+    pub defender: Entity,
+    pub chip_damage: f32,
+    pub blockstun: u32,
+    pub hitstop: u32,
 }
 
 pub struct AttackPlugin;
 
 impl Plugin for AttackPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, spawn_attack)
-            .add_systems(PostUpdate, (despawn_attack, attack_velocity));
+        app.rollback_component::<Attack>()
+            .rollback_component::<AttackProperties>()
+            .rollback_component::<AttackOwner>()
+            .rollback_component::<Collider>()
+            .rollback_component::<Sensor>()
+            .rollback_component::<ActiveEvents>()
+            .rollback_component::<ActiveCollisionTypes>()
+            .rollback_component::<CollisionGroups>()
+            .add_systems(
+                FixedUpdate,
+                (update_hitboxes, attack_hit, log_hits)
+                    .chain()
+                    .in_set(GameSet::Combat),
+            );
     }
 }
 
-fn spawn_attack(
-    time: Res<Time>,
+fn hitbox_collider(hitboxes: &[Hitbox], facing: f32) -> Collider {
+    Collider::compound(
+        hitboxes
+            .iter()
+            .map(|hitbox| {
+                (
+                    Vec2::new(hitbox.offset.x * facing, hitbox.offset.y),
+                    0.0,
+                    Collider::cuboid(hitbox.size.x / 2.0, hitbox.size.y / 2.0),
+                )
+            })
+            .collect(),
+    )
+}
+
+/// The move and hitboxes of a player who is in an active frame of an attack.
+pub fn active_hitboxes<'a>(
+    move_list: &'a MoveList,
+    state: PlayerState,
+    current_move: &CurrentMove,
+    state_timer: &StateTimer,
+) -> Option<(&'a MoveDef, &'a [Hitbox])> {
+    let move_def = move_list
+        .get(&current_move.name)
+        .filter(|_| state == PlayerState::Attack)?;
+    let MovePhase::Active(frame) = move_def.phase(state_timer.0.elapsed) else {
+        return None;
+    };
+    move_def
+        .hitboxes
+        .get(frame)
+        .filter(|hitboxes| !hitboxes.is_empty())
+        .map(|hitboxes| (move_def, hitboxes.as_slice()))
+}
+
+/// Where a player is in world space. Players are children of their LDtk layer, and their
+/// `GlobalTransform` lags behind by a tick until physics propagates it, so this combines
+/// the parent's, which never moves, with the player's current local transform.
+fn world_position(
+    transform: &Transform,
+    parent: Option<&Parent>,
+    parent_query: &Query<&GlobalTransform>,
+) -> Vec2 {
+    parent
+        .and_then(|parent| parent_query.get(parent.get()).ok())
+        .map_or(transform.translation, |parent_transform| {
+            parent_transform.transform_point(transform.translation)
+        })
+        .truncate()
+}
+
+/// Where a player is and which frame of which move they are in.
+type Attacker<'a> = (
+    Entity,
+    &'a PlayerId,
+    &'a Moveset,
+    &'a PlayerState,
+    &'a CurrentMove,
+    &'a StateTimer,
+    &'a Direction,
+    &'a Transform,
+    Option<&'a Parent>,
+);
+
+/// The sensor of an attack, which follows its owner's hitboxes.
+type AttackSensor<'a> = (Entity, &'a AttackOwner, &'a mut Collider, &'a mut Transform);
+
+/// Keeps one attack entity per player whose move is in an active frame, shaped like that
+/// frame's hitboxes, and despawns it outside of active frames.
+fn update_hitboxes(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
-    mut query: Query<
-        (
-            &mut AttackCooldown,
-            &AttackHeight,
-            &Direction,
-            &Transform,
-            &Velocity,
-        ),
-        With<Player>,
-    >,
-    mut ev_input: EventReader<PlayerInputEvent>,
+    player_query: Query<Attacker, With<Player>>,
+    parent_query: Query<&GlobalTransform>,
+    mut attack_query: Query<AttackSensor, (With<Attack>, Without<Player>)>,
 ) {
-    for (mut attack_cooldown, attack_height, player_direction, player_transform, velocity) in
-        query.iter_mut()
+    let mut attacks: HashMap<Entity, Entity> = attack_query
+        .iter()
+        .map(|(attack_entity, owner, _, _)| (owner.0, attack_entity))
+        .collect();
+
+    for (
+        player_entity,
+        player_id,
+        moveset,
+        state,
+        current_move,
+        state_timer,
+        direction,
+        player_transform,
+        parent,
+    ) in player_query.iter()
     {
-        attack_cooldown.0.tick(time.delta());
-        for input in ev_input.read() {
-            if !(input.0.contains(&PlayerInput::Attack)) {
-                return;
-            }
-            if !attack_cooldown.0.finished() {
-                return;
-            }
-            let width = 11.0;
-            let height = 11.0;
-            let mut x_attack_direction = 0.0;
-            let mut y_attack_direction = 0.0;
-            match player_direction {
-                Direction::Left => {
-                    x_attack_direction -= 20.0;
-                }
-                Direction::Right => {
-                    x_attack_direction += 20.0;
-                }
+        let existing = attacks.remove(&player_entity);
+        let active = active_hitboxes(&moveset.0, *state, current_move, state_timer);
+        let Some((move_def, hitboxes)) = active else {
+            if let Some(attack_entity) = existing {
+                commands.entity(attack_entity).despawn();
             }
-            match attack_height {
-                AttackHeight::Low => {
-                    y_attack_direction -= 10.0;
-                }
-                AttackHeight::Normal => {
-                    y_attack_direction += 0.0;
-                }
+            continue;
+        };
+
+        let facing = match direction {
+            Direction::Left => -1.0,
+            Direction::Right => 1.0,
+        };
+        let collider = hitbox_collider(hitboxes, facing);
+        let position = world_position(player_transform, parent, &parent_query);
+        let transform = Transform::from_translation(position.extend(0.0));
+        if let Some(attack_entity) = existing {
+            if let Ok((_, _, mut attack_collider, mut attack_transform)) =
+                attack_query.get_mut(attack_entity)
+            {
+                *attack_collider = collider;
+                *attack_transform = transform;
+                continue;
             }
-            attack_cooldown.0.reset();
-
-            let offset_vec = Vec2::new(704.0, 530.0);
-
-            commands.spawn((
-                AttackBundle {
-                    model: MaterialMesh2dBundle {
-                        mesh: meshes
-                            .add(Mesh::from(shape::Quad::new(Vec2::new(width, height))))
-                            .into(),
-                        material: materials
-                            .add(ColorMaterial::from(Color::rgb(1.0, 0.0, 0.0)))
-                            .into(),
-                        transform: Transform::from_translation(Vec3::new(
-                            (player_transform.translation.x + x_attack_direction) - offset_vec.x,
-                            (player_transform.translation.y + y_attack_direction) - offset_vec.y,
-                            0.0,
-                        )),
-                        ..Default::default()
-                    },
-                    timer: AttackDespawnTimer {
-                        timer: Timer::from_seconds(0.2, TimerMode::Once),
-                    },
-                    collider: Collider::cuboid(width - 30.0, height - 20.0),
-                    sensor: Sensor,
-                    velocity: *velocity,
-                    attack_properties: AttackProperties {
-                        damage: 10.0,
-                        range: 60.0,
-                    },
-                },
-                Attack,
-            ));
         }
+
+        commands.spawn((
+            AttackBundle {
+                transform: TransformBundle::from_transform(transform),
+                collider,
+                sensor: Sensor,
+                active_events: ActiveEvents::COLLISION_EVENTS,
+                // neither attacks nor players have a rigid body, so rapier treats both as static
+                active_collision_types: ActiveCollisionTypes::default()
+                    | ActiveCollisionTypes::STATIC_STATIC,
+                collision_groups: hitbox_groups(*player_id),
+                attack_properties: AttackProperties {
+                    damage: move_def.damage,
+                    chip_damage: move_def.chip_damage,
+                    guard: move_def.guard,
+                    hitstun: move_def.hitstun,
+                    blockstun: move_def.blockstun,
+                    knockback: Vec2::new(move_def.knockback.x * facing, move_def.knockback.y),
+                    hitstop: move_def.hitstop_frames(),
+                    shake: move_def.shake_trauma(),
+                    victims: Vec::new(),
+                },
+                owner: AttackOwner(player_entity),
+                rollback: Rollback,
+            },
+            Attack,
+        ));
+    }
+
+    // attacks whose owner is gone, e.g. after a level reset
+    for attack_entity in attacks.into_values() {
+        commands.entity(attack_entity).despawn();
     }
 }
 
-fn attack_velocity(
-    time: Res<Time>,
-    mut query: Query<(&mut Velocity, &mut Transform), With<Attack>>,
+pub fn attack_hit(
+    mut collision_events: EventReader<CollisionEvent>,
+    mut attack_query: Query<(&mut AttackProperties, &AttackOwner), With<Attack>>,
+    hurtbox_query: Query<&Hurtbox>,
+    mut player_query: Query<
+        (
+            &mut Health,
+            &mut CurrentMove,
+            &PlayerState,
+            &Guard,
+            &Transform,
+        ),
+        With<Player>,
+    >,
+    mut ev_hit: EventWriter<PlayerHitEvent>,
+    mut ev_block: EventWriter<PlayerBlockEvent>,
 ) {
-    for (velocity, mut transform) in query.iter_mut() {
-        transform.translation.x += velocity.velocity.x * time.delta_seconds();
-        transform.translation.y += velocity.velocity.y * time.delta_seconds();
+    for collision_event in collision_events.read() {
+        let CollisionEvent::Started(entity_1, entity_2, _) = *collision_event else {
+            continue;
+        };
+        let (attack_entity, hurtbox_entity) = if attack_query.contains(entity_1) {
+            (entity_1, entity_2)
+        } else if attack_query.contains(entity_2) {
+            (entity_2, entity_1)
+        } else {
+            continue;
+        };
+        let Ok(hurtbox) = hurtbox_query.get(hurtbox_entity) else {
+            continue;
+        };
+        let victim = hurtbox.owner;
+        let Ok((mut attack_properties, owner)) = attack_query.get_mut(attack_entity) else {
+            continue;
+        };
+        if owner.0 == victim || attack_properties.victims.contains(&victim) {
+            continue;
+        }
+        let Ok(attacker_x) = player_query
+            .get(owner.0)
+            .map(|(_, _, _, _, transform)| transform.translation.x)
+        else {
+            continue;
+        };
+        let Ok((mut health, _, state, guard, transform)) = player_query.get_mut(victim) else {
+            continue;
+        };
+        attack_properties.victims.push(victim);
+        let blocked = state.can_block()
+            && guard.blocks(
+                attack_properties.guard,
+                attacker_x - transform.translation.x,
+            );
+        if blocked {
+            health.current = (health.current - attack_properties.chip_damage).max(0.0);
+            ev_block.send(PlayerBlockEvent {
+                attacker: owner.0,
+                defender: victim,
+                chip_damage: attack_properties.chip_damage,
+                blockstun: attack_properties.blockstun,
+                hitstop: attack_properties.hitstop,
+            });
+            continue;
+        }
+
+        health.current = (health.current - attack_properties.damage).max(0.0);
+        if let Ok((_, mut current_move, _, _, _)) = player_query.get_mut(owner.0) {
+            current_move.connected = true;
+        }
+        ev_hit.send(PlayerHitEvent {
+            attacker: owner.0,
+            victim,
+            damage: attack_properties.damage,
+            hitstun: attack_properties.hitstun,
+            knockback: attack_properties.knockback,
+            hitstop: attack_properties.hitstop,
+            shake: attack_properties.shake,
+        });
     }
 }
 
-fn despawn_attack(
-    time: Res<Time>,
-    mut commands: Commands,
-    mut query: Query<(Entity, &mut AttackDespawnTimer), With<Attack>>,
+/// Logs every landed or blocked hit with the health the victim or defender has left, once
+/// per tick rather than again on rollback.
+fn log_hits(
+    resimulating: Res<Resimulating>,
+    player_query: Query<&Health, With<Player>>,
+    mut ev_hit: EventReader<PlayerHitEvent>,
+    mut ev_block: EventReader<PlayerBlockEvent>,
 ) {
-    for (entity, mut attack_timer) in query.iter_mut() {
-        attack_timer.timer.tick(time.delta());
-        if attack_timer.timer.finished() {
-            commands.entity(entity).despawn();
-        }
+    if resimulating.0 {
+        ev_hit.clear();
+        ev_block.clear();
+        return;
+    }
+    for hit in ev_hit.read() {
+        let Ok(health) = player_query.get(hit.victim) else {
+            continue;
+        };
+        debug!(
+            "{:?} hit {:?} for {} ({}/{} health left)",
+            hit.attacker, hit.victim, hit.damage, health.current, health.max
+        );
+    }
+    for block in ev_block.read() {
+        let Ok(health) = player_query.get(block.defender) else {
+            continue;
+        };
+        debug!(
+            "{:?} blocked {:?} and took {} chip damage ({}/{} health left)",
+            block.defender, block.attacker, block.chip_damage, health.current, health.max
+        );
     }
 }
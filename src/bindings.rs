@@ -0,0 +1,177 @@
+use crate::movement::PlayerInput;
+use crate::player::{PlayerId, MAX_PLAYERS};
+use bevy::utils::thiserror;
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    reflect::TypePath,
+    utils::BoxedFuture,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+const BINDINGS_PATH: &str = "input.bindings.ron";
+
+/// Keys and gamepad buttons for a single player slot.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PlayerBindings {
+    #[serde(default)]
+    pub keys: Vec<(KeyCode, PlayerInput)>,
+    #[serde(default)]
+    pub buttons: Vec<(GamepadButtonType, PlayerInput)>,
+}
+
+/// Physical key and button bindings for every player, indexed by `PlayerId`.
+///
+/// Loaded from `assets/input.bindings.ron` and replaced whenever the file changes. Until a
+/// valid file has loaded, the built-in defaults are used.
+#[derive(Resource, Asset, TypePath, Clone, Debug, Deserialize)]
+pub struct InputBindings {
+    pub players: Vec<PlayerBindings>,
+    #[serde(default = "default_stick_deadzone")]
+    pub stick_deadzone: f32,
+}
+
+impl InputBindings {
+    pub fn player(&self, player: PlayerId) -> Option<&PlayerBindings> {
+        self.players.get(player.0)
+    }
+}
+
+fn default_stick_deadzone() -> f32 {
+    0.3
+}
+
+fn default_buttons() -> Vec<(GamepadButtonType, PlayerInput)> {
+    vec![
+        (GamepadButtonType::DPadLeft, PlayerInput::Left),
+        (GamepadButtonType::DPadRight, PlayerInput::Right),
+        (GamepadButtonType::DPadUp, PlayerInput::Up),
+        (GamepadButtonType::DPadDown, PlayerInput::Down),
+        (GamepadButtonType::South, PlayerInput::Up),
+        (GamepadButtonType::West, PlayerInput::Attack),
+        (GamepadButtonType::North, PlayerInput::Attack),
+        (GamepadButtonType::Select, PlayerInput::ResetLevel),
+    ]
+}
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        Self {
+            players: vec![
+                PlayerBindings {
+                    keys: vec![
+                        (KeyCode::A, PlayerInput::Left),
+                        (KeyCode::D, PlayerInput::Right),
+                        (KeyCode::W, PlayerInput::Up),
+                        (KeyCode::S, PlayerInput::Down),
+                        (KeyCode::Space, PlayerInput::Attack),
+                        (KeyCode::R, PlayerInput::ResetLevel),
+                    ],
+                    buttons: default_buttons(),
+                },
+                PlayerBindings {
+                    keys: vec![
+                        (KeyCode::Left, PlayerInput::Left),
+                        (KeyCode::Right, PlayerInput::Right),
+                        (KeyCode::Up, PlayerInput::Up),
+                        (KeyCode::Down, PlayerInput::Down),
+                        (KeyCode::Numpad0, PlayerInput::Attack),
+                        (KeyCode::NumpadDecimal, PlayerInput::ResetLevel),
+                    ],
+                    buttons: default_buttons(),
+                },
+            ],
+            stick_deadzone: default_stick_deadzone(),
+        }
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum InputBindingsLoaderError {
+    #[error("could not read input bindings: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse input bindings: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+    #[error("input bindings define {0} players, expected between 1 and {1}")]
+    PlayerCount(usize, usize),
+    #[error("stick deadzone {0} is outside 0.0..1.0")]
+    StickDeadzone(f32),
+}
+
+#[derive(Default)]
+pub struct InputBindingsLoader;
+
+impl AssetLoader for InputBindingsLoader {
+    type Asset = InputBindings;
+    type Settings = ();
+    type Error = InputBindingsLoaderError;
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            let bindings = ron::de::from_bytes::<InputBindings>(&bytes)?;
+            if bindings.players.is_empty() || bindings.players.len() > MAX_PLAYERS {
+                return Err(InputBindingsLoaderError::PlayerCount(
+                    bindings.players.len(),
+                    MAX_PLAYERS,
+                ));
+            }
+            if !(0.0..1.0).contains(&bindings.stick_deadzone) {
+                return Err(InputBindingsLoaderError::StickDeadzone(
+                    bindings.stick_deadzone,
+                ));
+            }
+            Ok(bindings)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["bindings.ron"]
+    }
+}
+
+#[derive(Resource)]
+struct InputBindingsHandle(Handle<InputBindings>);
+
+pub struct BindingsPlugin;
+
+impl Plugin for BindingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InputBindings>()
+            .init_asset::<InputBindings>()
+            .init_asset_loader::<InputBindingsLoader>()
+            .add_systems(Startup, load_bindings)
+            .add_systems(PreUpdate, apply_bindings);
+    }
+}
+
+fn load_bindings(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(InputBindingsHandle(asset_server.load(BINDINGS_PATH)));
+}
+
+fn apply_bindings(
+    handle: Res<InputBindingsHandle>,
+    assets: Res<Assets<InputBindings>>,
+    mut ev_asset: EventReader<AssetEvent<InputBindings>>,
+    mut bindings: ResMut<InputBindings>,
+) {
+    for event in ev_asset.read() {
+        let (AssetEvent::Added { id } | AssetEvent::Modified { id }) = event else {
+            continue;
+        };
+        if *id != handle.0.id() {
+            continue;
+        }
+        if let Some(loaded) = assets.get(*id) {
+            info!("input bindings loaded from {}", BINDINGS_PATH);
+            *bindings = loaded.clone();
+        }
+    }
+}
@@ -1,28 +1,172 @@
+use crate::attack::{attack_hit, PlayerHitEvent};
+use crate::hitstop::ReducedMotion;
 use crate::player::Player;
+use crate::rollback::Resimulating;
+use crate::simulation::GameSet;
+use crate::world::LevelBounds;
 use bevy::prelude::*;
+use bevy::transform::TransformSystem;
+use bevy::window::PrimaryWindow;
+
+/// Projection scales the camera may zoom between; smaller is closer.
+const MIN_ZOOM: f32 = 0.4;
+const MAX_ZOOM: f32 = 0.8;
+/// Space kept around the players, in world pixels.
+const FRAMING_MARGIN: Vec2 = Vec2::new(96.0, 64.0);
+/// How quickly the camera catches up, per second; higher is snappier.
+const SMOOTHING: f32 = 6.0;
+/// Offset in world pixels at full trauma. Shake grows with the square of trauma.
+const MAX_SHAKE: Vec2 = Vec2::new(8.0, 6.0);
+/// Trauma lost per second.
+const TRAUMA_DECAY: f32 = 1.5;
+
+/// Where the camera is heading and how far it is zoomed out, before clamping to the level.
+#[derive(Component, Copy, Clone, Debug)]
+pub struct FightCamera {
+    pub center: Vec2,
+    pub zoom: f32,
+    /// Cleared while there is nobody to follow, so the camera cuts to the next fight
+    /// instead of panning across the world.
+    pub tracking: bool,
+    /// Screen shake, from 0 to 1, added by hits and decaying over time.
+    pub trauma: f32,
+}
+
+impl Default for FightCamera {
+    fn default() -> Self {
+        Self {
+            center: Vec2::ZERO,
+            zoom: MAX_ZOOM,
+            tracking: false,
+            trauma: 0.0,
+        }
+    }
+}
 
 pub struct CameraPlugin;
 
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(Startup, spawn_camera)
-            .add_systems(PostUpdate, camera_movement);
+            .add_systems(
+                FixedUpdate,
+                add_trauma.after(attack_hit).in_set(GameSet::Combat),
+            )
+            .add_systems(
+                PostUpdate,
+                camera_movement.before(TransformSystem::TransformPropagate),
+            );
     }
 }
 
 fn spawn_camera(mut commands: Commands) {
-    commands.spawn(Camera2dBundle::default());
+    commands.spawn((Camera2dBundle::default(), FightCamera::default()));
 }
 
+/// Runs with the hits it reacts to, so hits simulated again after a rollback do not shake
+/// the camera a second time.
+fn add_trauma(
+    reduced_motion: Res<ReducedMotion>,
+    resimulating: Res<Resimulating>,
+    mut ev_hit: EventReader<PlayerHitEvent>,
+    mut camera_query: Query<&mut FightCamera>,
+) {
+    let trauma: f32 = ev_hit.read().map(|hit| hit.shake).sum();
+    if reduced_motion.0 || resimulating.0 || trauma <= 0.0 {
+        return;
+    }
+    for mut camera in camera_query.iter_mut() {
+        camera.trauma = (camera.trauma + trauma).min(1.0);
+    }
+}
+
+/// Smooth pseudo-random offset in -1..1 on both axes, from overlapping sine waves.
+fn shake_offset(seconds: f32) -> Vec2 {
+    Vec2::new(
+        ((seconds * 47.0).sin() + (seconds * 31.0 + 1.3).sin()) / 2.0,
+        ((seconds * 53.0 + 0.7).sin() + (seconds * 37.0 + 2.1).sin()) / 2.0,
+    )
+}
+
+/// Keeps the camera's center inside `bounds` for a view of `view_size`, or centered on
+/// the level when it is smaller than the view.
+fn clamp_to_bounds(center: Vec2, view_size: Vec2, bounds: Rect) -> Vec2 {
+    let half_view = view_size / 2.0;
+    let clamp_axis = |center: f32, half_view: f32, min: f32, max: f32| {
+        if max - min <= half_view * 2.0 {
+            (min + max) / 2.0
+        } else {
+            center.clamp(min + half_view, max - half_view)
+        }
+    };
+    Vec2::new(
+        clamp_axis(center.x, half_view.x, bounds.min.x, bounds.max.x),
+        clamp_axis(center.y, half_view.y, bounds.min.y, bounds.max.y),
+    )
+}
+
+/// Frames every player: follows their midpoint and zooms out as they separate.
 fn camera_movement(
-    mut query: Query<&mut Transform, With<Camera>>,
-    player_query: Query<&Transform, (With<Player>, Without<Camera>)>,
+    time: Res<Time>,
+    level_bounds: Res<LevelBounds>,
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    mut camera_query: Query<(
+        &mut FightCamera,
+        &mut Transform,
+        &mut OrthographicProjection,
+    )>,
+    player_query: Query<&GlobalTransform, With<Player>>,
 ) {
-    let offset_vec = Vec2::new(704.0, 530.0);
-    for mut transform in query.iter_mut() {
-        for player_transform in player_query.iter() {
-            transform.translation.x = player_transform.translation.x - offset_vec.x;
-            // transform.translation.y = player_transform.translation.y;
+    let Ok(window) = window_query.get_single() else {
+        return;
+    };
+    let window_size = Vec2::new(window.width(), window.height());
+    if window_size.min_element() <= 0.0 {
+        return;
+    }
+    let positions: Vec<Vec2> = player_query
+        .iter()
+        .map(|transform| transform.translation().truncate())
+        .collect();
+    let Some(first) = positions.first() else {
+        for (mut camera, _, _) in camera_query.iter_mut() {
+            camera.tracking = false;
         }
+        return;
+    };
+    let (min, max) = positions
+        .iter()
+        .fold((*first, *first), |(min, max), position| {
+            (min.min(*position), max.max(*position))
+        });
+
+    let needed = (max - min) + FRAMING_MARGIN * 2.0;
+    let mut target_zoom = (needed / window_size)
+        .max_element()
+        .clamp(MIN_ZOOM, MAX_ZOOM);
+    if let Some(bounds) = level_bounds.0 {
+        // never zoom out past the level, unless the level is smaller than the closest zoom
+        let fit = (bounds.size() / window_size).min_element();
+        target_zoom = target_zoom.min(fit.max(MIN_ZOOM));
+    }
+    let target_center = (min + max) / 2.0;
+
+    let smoothed = 1.0 - (-SMOOTHING * time.delta_seconds()).exp();
+    for (mut camera, mut transform, mut projection) in camera_query.iter_mut() {
+        let blend = if camera.tracking { smoothed } else { 1.0 };
+        camera.tracking = true;
+        camera.center = camera.center.lerp(target_center, blend);
+        camera.zoom += (target_zoom - camera.zoom) * blend;
+        camera.trauma = (camera.trauma - TRAUMA_DECAY * time.delta_seconds()).max(0.0);
+        let shake = MAX_SHAKE * camera.trauma.powi(2) * shake_offset(time.elapsed_seconds());
+        let center = match level_bounds.0 {
+            Some(bounds) => {
+                clamp_to_bounds(camera.center + shake, window_size * camera.zoom, bounds)
+            }
+            None => camera.center + shake,
+        };
+        projection.scale = camera.zoom;
+        transform.translation.x = center.x;
+        transform.translation.y = center.y;
     }
 }
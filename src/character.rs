@@ -0,0 +1,374 @@
+use crate::animation::{AnimationSet, Animator};
+use crate::collision::{HurtboxShapes, Pushbox};
+use crate::movement::Velocity;
+use crate::moves::{MoveList, Moveset};
+use crate::player::{Health, Player, PlayerId, MAX_PLAYERS};
+use crate::replay::Replay;
+use crate::rollback::RollbackSession;
+use bevy::asset::LoadedFolder;
+use bevy::ecs::system::EntityCommands;
+use bevy::utils::thiserror;
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    reflect::TypePath,
+    utils::BoxedFuture,
+};
+use bevy_rapier2d::prelude::Collider;
+use serde::{Deserialize, Serialize};
+use std::any::TypeId;
+use thiserror::Error;
+
+/// Every `*.character.ron` file in here is a selectable fighter.
+const CHARACTERS_DIR: &str = "characters";
+
+/// How a fighter moves and takes hits. Copied onto the player when it spawns.
+#[derive(Component, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct CharacterStats {
+    pub walk_speed: f32,
+    /// Top speed of a dash.
+    pub run_speed: f32,
+    /// Upward speed of a jump, derived from the jump height and gravity.
+    pub jump_speed: f32,
+    /// Downward acceleration while airborne.
+    pub gravity: f32,
+    /// Fraction of the ground acceleration available in the air.
+    pub air_control: f32,
+    /// Knockback is divided by this.
+    pub weight: f32,
+}
+
+impl Default for CharacterStats {
+    fn default() -> Self {
+        Self {
+            walk_speed: 100.0,
+            run_speed: 160.0,
+            jump_speed: 200.0,
+            gravity: 400.0,
+            air_control: 1.0,
+            weight: 1.0,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CharacterDefFile {
+    name: String,
+    max_health: f32,
+    walk_speed: f32,
+    run_speed: f32,
+    /// Peak height of a jump, in pixels.
+    jump_height: f32,
+    gravity: f32,
+    air_control: f32,
+    weight: f32,
+    body_half_size: Vec2,
+    pushbox_half_size: Vec2,
+    hurtboxes: HurtboxShapes,
+    animations: String,
+    moves: String,
+}
+
+/// A selectable fighter: stats, collider and hurtbox sizes, sprite sheet and move list.
+///
+/// Loaded from `assets/characters/*.character.ron`. The animation set and move list are
+/// loaded from the paths the file names, so a new fighter only needs new asset files.
+#[derive(Asset, TypePath, Clone, Debug)]
+pub struct CharacterDef {
+    pub name: String,
+    pub max_health: f32,
+    pub stats: CharacterStats,
+    /// Half extents of the collider the player moves with.
+    pub body_half_size: Vec2,
+    pub pushbox_half_size: Vec2,
+    pub hurtboxes: HurtboxShapes,
+    pub animations: Handle<AnimationSet>,
+    pub moves: Handle<MoveList>,
+}
+
+impl CharacterDef {
+    pub fn settings(&self, moves: &MoveList) -> CharacterSettings {
+        CharacterSettings {
+            name: self.name.clone(),
+            max_health: self.max_health,
+            stats: self.stats,
+            body_half_size: self.body_half_size,
+            pushbox_half_size: self.pushbox_half_size,
+            hurtboxes: self.hurtboxes,
+            moves: moves.clone(),
+        }
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum CharacterDefLoaderError {
+    #[error("could not read character: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse character: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+    #[error("character {0:?} needs a positive {1}")]
+    NotPositive(String, &'static str),
+    #[error("character {0:?} needs a positive {1} hurtbox")]
+    NotPositiveHurtbox(String, &'static str),
+}
+
+#[derive(Default)]
+pub struct CharacterDefLoader;
+
+impl AssetLoader for CharacterDefLoader {
+    type Asset = CharacterDef;
+    type Settings = ();
+    type Error = CharacterDefLoaderError;
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            let file = ron::de::from_bytes::<CharacterDefFile>(&bytes)?;
+            let values = [
+                ("max_health", file.max_health),
+                ("walk_speed", file.walk_speed),
+                ("run_speed", file.run_speed),
+                ("jump_height", file.jump_height),
+                ("gravity", file.gravity),
+                ("weight", file.weight),
+                ("body_half_size", file.body_half_size.min_element()),
+                ("pushbox_half_size", file.pushbox_half_size.min_element()),
+            ];
+            if let Some((stat, _)) = values.iter().find(|(_, value)| *value <= 0.0) {
+                return Err(CharacterDefLoaderError::NotPositive(file.name, stat));
+            }
+            if let Some((hurtbox, _)) = file
+                .hurtboxes
+                .iter()
+                .find(|(_, shape)| shape.half_size.min_element() <= 0.0)
+            {
+                return Err(CharacterDefLoaderError::NotPositiveHurtbox(
+                    file.name, hurtbox,
+                ));
+            }
+            Ok(CharacterDef {
+                stats: CharacterStats {
+                    walk_speed: file.walk_speed,
+                    run_speed: file.run_speed,
+                    jump_speed: (2.0 * file.gravity * file.jump_height).sqrt(),
+                    gravity: file.gravity,
+                    air_control: file.air_control.clamp(0.0, 1.0),
+                    weight: file.weight,
+                },
+                name: file.name,
+                max_health: file.max_health,
+                body_half_size: file.body_half_size,
+                pushbox_half_size: file.pushbox_half_size,
+                hurtboxes: file.hurtboxes,
+                animations: load_context.load(file.animations),
+                moves: load_context.load(file.moves),
+            })
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["character.ron"]
+    }
+}
+
+/// Everything about a fighter the simulation depends on, resolved from its `CharacterDef`.
+/// Replays store this rather than the asset, so editing a character does not break them.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CharacterSettings {
+    pub name: String,
+    pub max_health: f32,
+    pub stats: CharacterStats,
+    pub body_half_size: Vec2,
+    pub pushbox_half_size: Vec2,
+    pub hurtboxes: HurtboxShapes,
+    pub moves: MoveList,
+}
+
+impl CharacterSettings {
+    /// Gives a freshly spawned player these settings, at full health and standing still.
+    pub fn apply(&self, player: &mut EntityCommands) {
+        player.insert((
+            self.stats,
+            Moveset(self.moves.clone()),
+            Collider::cuboid(self.body_half_size.x, self.body_half_size.y),
+            Pushbox {
+                half_size: self.pushbox_half_size,
+            },
+            self.hurtboxes,
+            Health {
+                current: self.max_health,
+                max: self.max_health,
+            },
+            Velocity {
+                velocity: Vec2::ZERO,
+                max_speed: self.stats.walk_speed,
+            },
+        ));
+    }
+}
+
+/// The fighter a player plays. Added once the character has loaded, which is when the
+/// player is ready to fight.
+#[derive(Component, Clone, Debug)]
+pub struct Character {
+    pub def: Handle<CharacterDef>,
+    pub settings: CharacterSettings,
+}
+
+#[derive(Clone, Debug)]
+pub struct RosterEntry {
+    pub name: String,
+    pub def: Handle<CharacterDef>,
+}
+
+/// Every selectable fighter, sorted by name, once the characters folder has loaded.
+#[derive(Resource)]
+pub struct CharacterRoster {
+    folder: Handle<LoadedFolder>,
+    pub characters: Vec<RosterEntry>,
+}
+
+/// Index into the roster of the fighter each player slot picked.
+#[derive(Resource, Copy, Clone, Debug)]
+pub struct CharacterSelection(pub [usize; MAX_PLAYERS]);
+
+impl Default for CharacterSelection {
+    fn default() -> Self {
+        // different fighters on each side until someone picks
+        Self(std::array::from_fn(|slot| slot))
+    }
+}
+
+pub struct CharacterPlugin;
+
+impl Plugin for CharacterPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<CharacterDef>()
+            .init_asset_loader::<CharacterDefLoader>()
+            .init_resource::<CharacterSelection>()
+            .add_systems(Startup, load_roster)
+            .add_systems(Update, (build_roster, apply_character, reload_movesets));
+    }
+}
+
+fn load_roster(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(CharacterRoster {
+        folder: asset_server.load_folder(CHARACTERS_DIR),
+        characters: Vec::new(),
+    });
+}
+
+fn build_roster(
+    asset_server: Res<AssetServer>,
+    folders: Res<Assets<LoadedFolder>>,
+    defs: Res<Assets<CharacterDef>>,
+    mut roster: ResMut<CharacterRoster>,
+) {
+    if !roster.characters.is_empty() || !asset_server.is_loaded_with_dependencies(&roster.folder) {
+        return;
+    }
+    let Some(folder) = folders.get(&roster.folder) else {
+        return;
+    };
+    let mut characters: Vec<RosterEntry> = folder
+        .handles
+        .iter()
+        .filter(|handle| handle.type_id() == TypeId::of::<CharacterDef>())
+        .map(|handle| handle.clone().typed::<CharacterDef>())
+        .filter_map(|def| {
+            defs.get(&def).map(|loaded| RosterEntry {
+                name: loaded.name.clone(),
+                def,
+            })
+        })
+        .collect();
+    characters.sort_by(|a, b| a.name.cmp(&b.name));
+    info!(
+        "characters loaded: {}",
+        characters
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    roster.characters = characters;
+}
+
+/// Players the level spawned that are not a fighter yet.
+type WithoutCharacter = (With<Player>, Without<Character>);
+
+/// Turns each player the level spawned into the fighter picked for its slot. In netplay,
+/// waits until the peers have told each other their picks.
+fn apply_character(
+    mut commands: Commands,
+    roster: Res<CharacterRoster>,
+    selection: Res<CharacterSelection>,
+    session: Option<Res<RollbackSession>>,
+    defs: Res<Assets<CharacterDef>>,
+    move_lists: Res<Assets<MoveList>>,
+    query: Query<(Entity, &PlayerId), WithoutCharacter>,
+) {
+    if roster.characters.is_empty() || !session.is_none_or(|session| session.agreed()) {
+        return;
+    }
+    for (entity, player_id) in query.iter() {
+        let index = selection.0.get(player_id.0).copied().unwrap_or(0);
+        let entry = &roster.characters[index % roster.characters.len()];
+        let Some(def) = defs.get(&entry.def) else {
+            continue;
+        };
+        let Some(moves) = move_lists.get(&def.moves) else {
+            continue;
+        };
+        let settings = def.settings(moves);
+        let mut player = commands.entity(entity);
+        settings.apply(&mut player);
+        // picked up again with the character's own sprite sheet
+        player.remove::<Animator>().insert(Character {
+            def: entry.def.clone(),
+            settings,
+        });
+    }
+}
+
+/// Edited move lists take effect on the fighters using them right away. Other changes to
+/// a character apply the next time it spawns.
+///
+/// Not while a replay or netplay runs, as the moves recorded or agreed on have to stay.
+fn reload_movesets(
+    defs: Res<Assets<CharacterDef>>,
+    move_lists: Res<Assets<MoveList>>,
+    replay: Res<Replay>,
+    session: Option<Res<RollbackSession>>,
+    mut ev_asset: EventReader<AssetEvent<MoveList>>,
+    mut query: Query<(&mut Character, &mut Moveset), With<Player>>,
+) {
+    if !matches!(replay.as_ref(), Replay::Idle) || session.is_some() {
+        ev_asset.clear();
+        return;
+    }
+    for event in ev_asset.read() {
+        let AssetEvent::Modified { id } = event else {
+            continue;
+        };
+        let Some(moves) = move_lists.get(*id) else {
+            continue;
+        };
+        for (mut character, mut moveset) in query.iter_mut() {
+            if defs
+                .get(&character.def)
+                .is_some_and(|def| def.moves.id() == *id)
+            {
+                info!("moves of {} reloaded", character.settings.name);
+                character.settings.moves = moves.clone();
+                moveset.0 = moves.clone();
+            }
+        }
+    }
+}
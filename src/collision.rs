@@ -0,0 +1,251 @@
+use crate::attack::attack_hit;
+use crate::player::{apply_velocity, Direction, Player, PlayerId, MAX_PLAYERS};
+use crate::player_state::{Guard, PlayerState};
+use crate::rollback::{Rollback, RollbackAppExt};
+use crate::simulation::GameSet;
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Level geometry.
+pub const WORLD_GROUP: Group = Group::GROUP_1;
+/// Player bodies, which only collide with the level.
+pub const BODY_GROUP: Group = Group::GROUP_2;
+/// Attack hitboxes, which only test against opposing hurtboxes.
+pub const HITBOX_GROUP: Group = Group::GROUP_3;
+/// The first of `MAX_PLAYERS` consecutive groups, one per player slot.
+const FIRST_HURTBOX_GROUP: Group = Group::GROUP_4;
+
+/// Most a pushbox moves its player per tick, so separation looks like a shove, not a snap.
+const MAX_PUSH_PER_TICK: f32 = 4.0;
+
+pub fn hurtbox_group(player: PlayerId) -> Group {
+    Group::from_bits_truncate(FIRST_HURTBOX_GROUP.bits() << player.0)
+}
+
+/// Groups for a hitbox owned by `owner`: it sees every hurtbox except the owner's.
+pub fn hitbox_groups(owner: PlayerId) -> CollisionGroups {
+    let opponents = (0..MAX_PLAYERS)
+        .map(PlayerId)
+        .filter(|player| *player != owner)
+        .fold(Group::NONE, |groups, player| groups | hurtbox_group(player));
+    CollisionGroups::new(HITBOX_GROUP, opponents)
+}
+
+pub fn body_groups() -> CollisionGroups {
+    CollisionGroups::new(BODY_GROUP, WORLD_GROUP)
+}
+
+pub fn world_groups() -> CollisionGroups {
+    CollisionGroups::new(WORLD_GROUP, BODY_GROUP)
+}
+
+/// Sensor child of a player that attacks have to touch to land a hit. Its shape follows
+/// the player's state.
+#[derive(Component, Copy, Clone, Debug)]
+pub struct Hurtbox {
+    pub owner: Entity,
+    pub offset: Vec2,
+    pub half_size: Vec2,
+}
+
+/// A hurtbox relative to the player's center while facing right.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HurtboxShape {
+    pub offset: Vec2,
+    pub half_size: Vec2,
+}
+
+impl HurtboxShape {
+    const fn new(offset: Vec2, half_size: Vec2) -> Self {
+        Self { offset, half_size }
+    }
+}
+
+/// The hurtbox a fighter has in each state, from its character.
+#[derive(Component, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct HurtboxShapes {
+    pub standing: HurtboxShape,
+    /// Also used while blocking low.
+    pub crouching: HurtboxShape,
+    pub airborne: HurtboxShape,
+    pub attacking: HurtboxShape,
+    pub juggled: HurtboxShape,
+    pub knocked_down: HurtboxShape,
+}
+
+impl Default for HurtboxShapes {
+    fn default() -> Self {
+        Self {
+            standing: HurtboxShape::new(Vec2::ZERO, Vec2::new(12.0, 20.0)),
+            crouching: HurtboxShape::new(Vec2::new(0.0, -8.0), Vec2::new(12.0, 12.0)),
+            airborne: HurtboxShape::new(Vec2::new(0.0, 4.0), Vec2::new(11.0, 16.0)),
+            attacking: HurtboxShape::new(Vec2::new(4.0, 0.0), Vec2::new(14.0, 20.0)),
+            juggled: HurtboxShape::new(Vec2::ZERO, Vec2::new(16.0, 12.0)),
+            knocked_down: HurtboxShape::new(Vec2::new(0.0, -14.0), Vec2::new(18.0, 6.0)),
+        }
+    }
+}
+
+impl HurtboxShapes {
+    pub fn for_state(&self, state: PlayerState, guard: &Guard) -> HurtboxShape {
+        match state {
+            PlayerState::Crouch => self.crouching,
+            PlayerState::Blockstun if guard.crouching => self.crouching,
+            PlayerState::Jump | PlayerState::Fall => self.airborne,
+            PlayerState::Attack => self.attacking,
+            PlayerState::Juggle => self.juggled,
+            PlayerState::Knockdown => self.knocked_down,
+            _ => self.standing,
+        }
+    }
+
+    /// Every shape with its name, for validating a character.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, HurtboxShape)> {
+        [
+            ("standing", self.standing),
+            ("crouching", self.crouching),
+            ("airborne", self.airborne),
+            ("attacking", self.attacking),
+            ("juggled", self.juggled),
+            ("knocked_down", self.knocked_down),
+        ]
+        .into_iter()
+    }
+}
+
+/// Horizontal extent players may not share with each other.
+#[derive(Component, Copy, Clone, Debug)]
+pub struct Pushbox {
+    pub half_size: Vec2,
+}
+
+impl Default for Pushbox {
+    fn default() -> Self {
+        Self {
+            half_size: Vec2::new(10.0, 20.0),
+        }
+    }
+}
+
+pub struct CollisionPlugin;
+
+impl Plugin for CollisionPlugin {
+    fn build(&self, app: &mut App) {
+        app.rollback_component::<Hurtbox>()
+            .add_systems(Update, spawn_hurtboxes)
+            .add_systems(
+                FixedUpdate,
+                update_hurtboxes.before(attack_hit).in_set(GameSet::Combat),
+            )
+            .add_systems(
+                FixedUpdate,
+                separate_players
+                    .after(apply_velocity)
+                    .in_set(GameSet::Movement),
+            );
+    }
+}
+
+fn spawn_hurtboxes(
+    mut commands: Commands,
+    query: Query<(Entity, &PlayerId, &HurtboxShapes), Added<Player>>,
+) {
+    for (player_entity, player_id, shapes) in query.iter() {
+        let HurtboxShape { offset, half_size } = shapes.standing;
+        commands.entity(player_entity).with_children(|player| {
+            player.spawn((
+                Hurtbox {
+                    owner: player_entity,
+                    offset,
+                    half_size,
+                },
+                TransformBundle::from_transform(Transform::from_translation(offset.extend(0.0))),
+                Collider::cuboid(half_size.x, half_size.y),
+                Sensor,
+                // neither attacks nor players have a rigid body, so rapier treats both as static
+                ActiveCollisionTypes::default() | ActiveCollisionTypes::STATIC_STATIC,
+                CollisionGroups::new(hurtbox_group(*player_id), HITBOX_GROUP),
+                Rollback,
+            ));
+        });
+    }
+}
+
+fn update_hurtboxes(
+    player_query: Query<(&PlayerState, &Guard, &Direction, &HurtboxShapes), With<Player>>,
+    mut hurtbox_query: Query<(&mut Hurtbox, &mut Collider, &mut Transform)>,
+) {
+    for (mut hurtbox, mut collider, mut transform) in hurtbox_query.iter_mut() {
+        let Ok((state, guard, direction, shapes)) = player_query.get(hurtbox.owner) else {
+            continue;
+        };
+        let HurtboxShape {
+            mut offset,
+            half_size,
+        } = shapes.for_state(*state, guard);
+        if let Direction::Left = direction {
+            offset.x = -offset.x;
+        }
+        if hurtbox.offset == offset && hurtbox.half_size == half_size {
+            continue;
+        }
+        hurtbox.offset = offset;
+        hurtbox.half_size = half_size;
+        *collider = Collider::cuboid(half_size.x, half_size.y);
+        transform.translation = offset.extend(0.0);
+    }
+}
+
+/// Pushes overlapping players apart along x, lower slots to the left on an exact tie. This
+/// also slides a player off an opponent they landed on.
+fn separate_players(
+    mut query: Query<
+        (
+            &PlayerId,
+            &Transform,
+            &Pushbox,
+            &mut KinematicCharacterController,
+        ),
+        With<Player>,
+    >,
+) {
+    let mut players: Vec<(PlayerId, Vec2, Vec2)> = query
+        .iter()
+        .map(|(player_id, transform, pushbox, _)| {
+            (
+                *player_id,
+                transform.translation.truncate(),
+                pushbox.half_size,
+            )
+        })
+        .collect();
+    players.sort_by_key(|(player_id, _, _)| *player_id);
+
+    let mut pushes: Vec<(PlayerId, f32)> = Vec::new();
+    for (index, (first, first_position, first_half)) in players.iter().enumerate() {
+        for (second, second_position, second_half) in players.iter().skip(index + 1) {
+            let delta = *second_position - *first_position;
+            let overlap = *first_half + *second_half - delta.abs();
+            if overlap.x <= 0.0 || overlap.y <= 0.0 {
+                continue;
+            }
+            let side = if delta.x < 0.0 { -1.0 } else { 1.0 };
+            let push = (overlap.x / 2.0).min(MAX_PUSH_PER_TICK) * side;
+            pushes.push((*first, -push));
+            pushes.push((*second, push));
+        }
+    }
+
+    for (player_id, _, _, mut controller) in query.iter_mut() {
+        let push: f32 = pushes
+            .iter()
+            .filter(|(pushed, _)| pushed == player_id)
+            .map(|(_, push)| push)
+            .sum();
+        if push != 0.0 {
+            let translation = controller.translation.unwrap_or_default();
+            controller.translation = Some(translation + Vec2::new(push, 0.0));
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use crate::animation::AnimationEvent;
+use crate::attack::{
+    active_hitboxes, attack_hit, Attack, AttackOwner, PlayerBlockEvent, PlayerHitEvent,
+};
+use crate::collision::{Hurtbox, Pushbox};
+use crate::moves::{MovePhase, Moveset};
+use crate::player::{Direction, Player};
+use crate::player_state::{CurrentMove, PlayerState, StateTimer};
+use crate::rollback::Resimulating;
+use crate::simulation::GameSet;
+use bevy::prelude::*;
+use bevy_rapier2d::render::DebugRenderContext;
+
+const OVERLAY_KEY: KeyCode = KeyCode::F1;
+const RAPIER_RENDER_KEY: KeyCode = KeyCode::F2;
+const HURTBOX_COLOR: Color = Color::rgb(0.2, 0.4, 1.0);
+const HITBOX_COLOR: Color = Color::rgb(1.0, 0.1, 0.1);
+const PUSHBOX_COLOR: Color = Color::rgb(1.0, 0.9, 0.1);
+
+/// Whether the fighting-game box overlay is drawn. F1 toggles it, F2 toggles Rapier's own
+/// collider renderer.
+#[derive(Resource, Default, Debug)]
+pub struct DebugOverlay {
+    pub enabled: bool,
+}
+
+/// Frame advantage of the last move this player landed: the opponent's stun minus the
+/// attacker's remaining frames, so positive means the attacker recovers first.
+#[derive(Component, Copy, Clone, Debug, Default)]
+pub struct FrameAdvantage {
+    pub on_hit: Option<i32>,
+    pub on_block: Option<i32>,
+}
+
+/// The last animation marker this player reached, to check markers against frame data.
+#[derive(Component, Clone, Debug, Default)]
+pub struct LastAnimationMarker {
+    pub animation: String,
+    pub marker: String,
+}
+
+#[derive(Component)]
+struct FrameDataLabel;
+
+pub struct DebugOverlayPlugin;
+
+impl Plugin for DebugOverlayPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DebugOverlay>()
+            .add_systems(
+                Update,
+                (
+                    toggle_overlay,
+                    spawn_frame_data_labels,
+                    record_animation_markers,
+                    (draw_boxes, update_frame_data_labels)
+                        .run_if(|overlay: Res<DebugOverlay>| overlay.enabled),
+                )
+                    .chain(),
+            )
+            .add_systems(
+                FixedUpdate,
+                record_frame_advantage
+                    .after(attack_hit)
+                    .in_set(GameSet::Combat),
+            );
+    }
+}
+
+fn toggle_overlay(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut overlay: ResMut<DebugOverlay>,
+    mut rapier_render: ResMut<DebugRenderContext>,
+    mut label_query: Query<&mut Visibility, With<FrameDataLabel>>,
+) {
+    if keyboard_input.just_pressed(OVERLAY_KEY) {
+        overlay.enabled = !overlay.enabled;
+        for mut visibility in label_query.iter_mut() {
+            *visibility = if overlay.enabled {
+                Visibility::Inherited
+            } else {
+                Visibility::Hidden
+            };
+        }
+    }
+    if keyboard_input.just_pressed(RAPIER_RENDER_KEY) {
+        rapier_render.enabled = !rapier_render.enabled;
+    }
+}
+
+fn spawn_frame_data_labels(
+    mut commands: Commands,
+    overlay: Res<DebugOverlay>,
+    query: Query<Entity, Added<Player>>,
+) {
+    for player_entity in query.iter() {
+        commands
+            .entity(player_entity)
+            .insert((FrameAdvantage::default(), LastAnimationMarker::default()))
+            .with_children(|player| {
+                player.spawn((
+                    Text2dBundle {
+                        text: Text::from_section(
+                            "",
+                            TextStyle {
+                                font_size: 12.0,
+                                color: Color::WHITE,
+                                ..default()
+                            },
+                        ),
+                        transform: Transform::from_xyz(0.0, 36.0, 10.0),
+                        visibility: if overlay.enabled {
+                            Visibility::Inherited
+                        } else {
+                            Visibility::Hidden
+                        },
+                        ..default()
+                    },
+                    FrameDataLabel,
+                ));
+            });
+    }
+}
+
+fn draw_boxes(
+    mut gizmos: Gizmos,
+    hurtbox_query: Query<(&Hurtbox, &GlobalTransform)>,
+    pushbox_query: Query<(&Pushbox, &GlobalTransform), With<Player>>,
+    attack_query: Query<(&AttackOwner, &GlobalTransform), With<Attack>>,
+    player_query: Query<
+        (
+            &Moveset,
+            &PlayerState,
+            &CurrentMove,
+            &StateTimer,
+            &Direction,
+        ),
+        With<Player>,
+    >,
+) {
+    for (hurtbox, transform) in hurtbox_query.iter() {
+        gizmos.rect_2d(
+            transform.translation().truncate(),
+            0.0,
+            hurtbox.half_size * 2.0,
+            HURTBOX_COLOR,
+        );
+    }
+    for (pushbox, transform) in pushbox_query.iter() {
+        gizmos.rect_2d(
+            transform.translation().truncate(),
+            0.0,
+            pushbox.half_size * 2.0,
+            PUSHBOX_COLOR,
+        );
+    }
+    for (owner, transform) in attack_query.iter() {
+        let Ok((moveset, state, current_move, state_timer, direction)) = player_query.get(owner.0)
+        else {
+            continue;
+        };
+        let Some((_, hitboxes)) = active_hitboxes(&moveset.0, *state, current_move, state_timer)
+        else {
+            continue;
+        };
+        let facing = match direction {
+            Direction::Left => -1.0,
+            Direction::Right => 1.0,
+        };
+        for hitbox in hitboxes {
+            let offset = Vec2::new(hitbox.offset.x * facing, hitbox.offset.y);
+            gizmos.rect_2d(
+                transform.translation().truncate() + offset,
+                0.0,
+                hitbox.size,
+                HITBOX_COLOR,
+            );
+        }
+    }
+}
+
+/// What a player's frame data label shows.
+type FrameData<'a> = (
+    &'a Moveset,
+    &'a PlayerState,
+    &'a CurrentMove,
+    &'a StateTimer,
+    &'a FrameAdvantage,
+    &'a LastAnimationMarker,
+    &'a Children,
+);
+
+fn update_frame_data_labels(
+    player_query: Query<FrameData, With<Player>>,
+    mut label_query: Query<&mut Text, With<FrameDataLabel>>,
+) {
+    fn signed(advantage: Option<i32>) -> String {
+        advantage.map_or(String::from("-"), |frames| format!("{:+}", frames))
+    }
+
+    for (moveset, state, current_move, state_timer, advantage, marker, children) in
+        player_query.iter()
+    {
+        let move_data = moveset
+            .0
+            .get(&current_move.name)
+            .filter(|_| *state == PlayerState::Attack)
+            .map(|move_def| {
+                let frame = state_timer.0.elapsed;
+                let phase = match move_def.phase(frame) {
+                    MovePhase::Startup => "startup",
+                    MovePhase::Active(_) => "active",
+                    MovePhase::Recovery => "recovery",
+                };
+                format!(
+                    "{} {}/{}/{} {} {}\n",
+                    current_move.name,
+                    move_def.startup,
+                    move_def.active,
+                    move_def.recovery,
+                    phase,
+                    frame + 1
+                )
+            })
+            .unwrap_or_default();
+        let contents = format!(
+            "{}{:?} {}\nhit {} block {}\nmarker {}:{}",
+            move_data,
+            state,
+            state_timer.0.elapsed,
+            signed(advantage.on_hit),
+            signed(advantage.on_block),
+            marker.animation,
+            marker.marker
+        );
+        for child in children.iter() {
+            if let Ok(mut text) = label_query.get_mut(*child) {
+                text.sections[0].value = contents.clone();
+            }
+        }
+    }
+}
+
+fn record_animation_markers(
+    mut query: Query<&mut LastAnimationMarker>,
+    mut ev_animation: EventReader<AnimationEvent>,
+) {
+    for event in ev_animation.read() {
+        if let Ok(mut last) = query.get_mut(event.entity) {
+            last.animation = event.animation.clone();
+            last.marker = event.marker.clone();
+        }
+    }
+}
+
+fn record_frame_advantage(
+    resimulating: Res<Resimulating>,
+    mut query: Query<(&Moveset, &CurrentMove, &StateTimer, &mut FrameAdvantage), With<Player>>,
+    mut ev_hit: EventReader<PlayerHitEvent>,
+    mut ev_block: EventReader<PlayerBlockEvent>,
+) {
+    if resimulating.0 {
+        ev_hit.clear();
+        ev_block.clear();
+        return;
+    }
+    let remaining_frames =
+        |moveset: &Moveset, current_move: &CurrentMove, state_timer: &StateTimer| {
+            moveset
+                .0
+                .get(&current_move.name)
+                .map_or(0, |move_def| move_def.total_frames() as i32)
+                - state_timer.0.elapsed as i32
+        };
+    for hit in ev_hit.read() {
+        if let Ok((moveset, current_move, state_timer, mut advantage)) = query.get_mut(hit.attacker)
+        {
+            advantage.on_hit =
+                Some(hit.hitstun as i32 - remaining_frames(moveset, current_move, state_timer));
+        }
+    }
+    for block in ev_block.read() {
+        if let Ok((moveset, current_move, state_timer, mut advantage)) =
+            query.get_mut(block.attacker)
+        {
+            advantage.on_block =
+                Some(block.blockstun as i32 - remaining_frames(moveset, current_move, state_timer));
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use crate::character::{CharacterRoster, CharacterSelection};
+use crate::player::MAX_PLAYERS;
+use crate::rollback::RollbackSession;
+use crate::rounds::{MatchOutcome, MatchState, RoundEnd};
+use crate::world::LdtkProjectHandle;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy_ecs_ldtk::prelude::*;
+
+#[derive(States, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum GameState {
+    #[default]
+    Title,
+    CharacterSelect,
+    StageSelect,
+    Fight,
+    MatchOver,
+    Paused,
+}
+
+/// Despawned, with its children, when the given state is left.
+#[derive(Component, Copy, Clone, Debug)]
+pub struct DespawnOnExit(pub GameState);
+
+/// The K.O. or time out screen of the round just decided.
+#[derive(Component)]
+struct RoundOverScreen;
+
+/// The player slot currently picking a character.
+#[derive(Resource, Default)]
+struct CharacterSelectCursor(usize);
+
+pub struct GameStatePlugin;
+
+impl Plugin for GameStatePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_state::<GameState>();
+        // replays and netplay drop straight into the fight
+        let skip_menus =
+            std::env::args().any(|arg| arg == "--fight" || arg == "--replay" || arg == "--netplay");
+        if skip_menus {
+            app.world
+                .resource_mut::<NextState<GameState>>()
+                .set(GameState::Fight);
+        }
+
+        for state in [
+            GameState::Title,
+            GameState::CharacterSelect,
+            GameState::StageSelect,
+            GameState::Fight,
+            GameState::MatchOver,
+            GameState::Paused,
+        ] {
+            app.add_systems(
+                OnExit(state),
+                move |mut commands: Commands, query: Query<(Entity, &DespawnOnExit)>| {
+                    for (entity, despawn_on_exit) in query.iter() {
+                        if despawn_on_exit.0 == state {
+                            commands.entity(entity).despawn_recursive();
+                        }
+                    }
+                },
+            );
+        }
+
+        app.add_systems(OnEnter(GameState::Title), spawn_title_screen)
+            .add_systems(OnEnter(GameState::CharacterSelect), spawn_character_select)
+            .add_systems(OnEnter(GameState::StageSelect), spawn_stage_select)
+            .add_systems(OnEnter(GameState::MatchOver), spawn_results)
+            .add_systems(OnEnter(GameState::Paused), spawn_pause_screen)
+            .add_systems(
+                Update,
+                (
+                    advance_menu
+                        .run_if(in_state(GameState::Title).or_else(in_state(GameState::MatchOver))),
+                    select_character.run_if(in_state(GameState::CharacterSelect)),
+                    select_stage.run_if(in_state(GameState::StageSelect)),
+                    toggle_pause
+                        .run_if(in_state(GameState::Fight).or_else(in_state(GameState::Paused))),
+                    show_round_over.run_if(in_state(GameState::Fight)),
+                ),
+            );
+    }
+}
+
+/// Keyboard and gamepad buttons that drive the menus.
+#[derive(SystemParam)]
+struct MenuInput<'w> {
+    keyboard_input: Res<'w, Input<KeyCode>>,
+    gamepad_input: Res<'w, Input<GamepadButton>>,
+    gamepads: Res<'w, Gamepads>,
+}
+
+impl MenuInput<'_> {
+    fn gamepad_just_pressed(&self, button_type: GamepadButtonType) -> bool {
+        self.gamepads.iter().any(|gamepad| {
+            self.gamepad_input
+                .just_pressed(GamepadButton::new(gamepad, button_type))
+        })
+    }
+
+    fn confirm(&self) -> bool {
+        self.keyboard_input
+            .any_just_pressed([KeyCode::Return, KeyCode::Space])
+            || self.gamepad_just_pressed(GamepadButtonType::South)
+            || self.gamepad_just_pressed(GamepadButtonType::Start)
+    }
+
+    fn pause(&self) -> bool {
+        self.keyboard_input.just_pressed(KeyCode::Escape)
+            || self.gamepad_just_pressed(GamepadButtonType::Start)
+    }
+
+    /// -1 or 1 when left or right was pressed.
+    fn horizontal(&self) -> Option<isize> {
+        if self.keyboard_input.just_pressed(KeyCode::Left)
+            || self.gamepad_just_pressed(GamepadButtonType::DPadLeft)
+        {
+            Some(-1)
+        } else if self.keyboard_input.just_pressed(KeyCode::Right)
+            || self.gamepad_just_pressed(GamepadButtonType::DPadRight)
+        {
+            Some(1)
+        } else {
+            None
+        }
+    }
+}
+
+/// Full-screen column of centered lines; the first is drawn as a heading.
+fn spawn_screen(
+    commands: &mut Commands,
+    state: GameState,
+    lines: &[String],
+    dimmed: bool,
+) -> Entity {
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    flex_direction: FlexDirection::Column,
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    row_gap: Val::Px(12.0),
+                    ..default()
+                },
+                background_color: if dimmed {
+                    Color::rgba(0.0, 0.0, 0.0, 0.6).into()
+                } else {
+                    Color::rgb(0.08, 0.08, 0.12).into()
+                },
+                ..default()
+            },
+            DespawnOnExit(state),
+        ))
+        .with_children(|screen| {
+            for (index, line) in lines.iter().enumerate() {
+                screen.spawn(TextBundle::from_section(
+                    line.clone(),
+                    TextStyle {
+                        font_size: if index == 0 { 48.0 } else { 20.0 },
+                        color: Color::WHITE,
+                        ..default()
+                    },
+                ));
+            }
+        })
+        .id()
+}
+
+fn spawn_title_screen(mut commands: Commands) {
+    spawn_screen(
+        &mut commands,
+        GameState::Title,
+        &[
+            String::from("Fight Game"),
+            String::from("Press Enter or Start"),
+        ],
+        false,
+    );
+}
+
+/// Lists every slot's pick, with arrows around the one being chosen.
+fn spawn_character_screen(
+    commands: &mut Commands,
+    roster: &CharacterRoster,
+    selection: &CharacterSelection,
+    picking: usize,
+) {
+    let mut lines = vec![String::from("Character Select")];
+    for (slot, index) in selection.0.iter().enumerate() {
+        let name: &str = if roster.characters.is_empty() {
+            "Loading..."
+        } else {
+            &roster.characters[index % roster.characters.len()].name
+        };
+        lines.push(if slot == picking {
+            format!("Player {}: < {} >", slot + 1, name)
+        } else {
+            format!("Player {}: {}", slot + 1, name)
+        });
+    }
+    lines.push(String::from(
+        "Left/Right to choose, Enter or Start to confirm",
+    ));
+    spawn_screen(commands, GameState::CharacterSelect, &lines, false);
+}
+
+fn spawn_character_select(
+    mut commands: Commands,
+    roster: Res<CharacterRoster>,
+    selection: Res<CharacterSelection>,
+) {
+    commands.insert_resource(CharacterSelectCursor::default());
+    spawn_character_screen(&mut commands, &roster, &selection, 0);
+}
+
+fn stage_name(
+    level_selection: &LevelSelection,
+    ldtk_project: Option<&LdtkProject>,
+) -> Option<String> {
+    let LevelSelection::Indices(indices) = level_selection else {
+        return None;
+    };
+    ldtk_project?
+        .iter_raw_levels()
+        .nth(indices.level)
+        .map(|level| level.identifier.clone())
+}
+
+fn spawn_stage_screen(commands: &mut Commands, name: &str) {
+    spawn_screen(
+        commands,
+        GameState::StageSelect,
+        &[
+            String::from("Stage Select"),
+            format!("< {} >", name),
+            String::from("Left/Right to choose, Enter or Start to fight"),
+        ],
+        false,
+    );
+}
+
+fn spawn_stage_select(
+    mut commands: Commands,
+    level_selection: Res<LevelSelection>,
+    ldtk_handle: Res<LdtkProjectHandle>,
+    ldtk_projects: Res<Assets<LdtkProject>>,
+) {
+    let name = stage_name(&level_selection, ldtk_projects.get(&ldtk_handle.0))
+        .unwrap_or_else(|| String::from("Default stage"));
+    spawn_stage_screen(&mut commands, &name);
+}
+
+fn advance_menu(
+    state: Res<State<GameState>>,
+    mut next_state: ResMut<NextState<GameState>>,
+    menu_input: MenuInput,
+) {
+    if !menu_input.confirm() {
+        return;
+    }
+    next_state.set(match state.get() {
+        GameState::Title => GameState::CharacterSelect,
+        _ => GameState::Title,
+    });
+}
+
+/// Each slot picks in turn; once the last one confirms, on to the stage.
+fn select_character(
+    mut commands: Commands,
+    mut next_state: ResMut<NextState<GameState>>,
+    mut cursor: ResMut<CharacterSelectCursor>,
+    mut selection: ResMut<CharacterSelection>,
+    menu_input: MenuInput,
+    roster: Res<CharacterRoster>,
+    screen_query: Query<Entity, With<DespawnOnExit>>,
+) {
+    if menu_input.confirm() {
+        cursor.0 += 1;
+        if cursor.0 == MAX_PLAYERS {
+            next_state.set(GameState::StageSelect);
+            return;
+        }
+    } else if let Some(step) = menu_input.horizontal() {
+        let count = roster.characters.len() as isize;
+        if count == 0 {
+            return;
+        }
+        let current = &mut selection.0[cursor.0];
+        *current = (*current as isize + step).rem_euclid(count) as usize;
+    } else {
+        return;
+    }
+
+    // rebuild the screen to show the new picks
+    for entity in screen_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    spawn_character_screen(&mut commands, &roster, &selection, cursor.0);
+}
+
+fn select_stage(
+    mut commands: Commands,
+    mut next_state: ResMut<NextState<GameState>>,
+    mut level_selection: ResMut<LevelSelection>,
+    menu_input: MenuInput,
+    ldtk_handle: Res<LdtkProjectHandle>,
+    ldtk_projects: Res<Assets<LdtkProject>>,
+    screen_query: Query<Entity, With<DespawnOnExit>>,
+) {
+    if menu_input.confirm() {
+        next_state.set(GameState::Fight);
+        return;
+    }
+    let Some(step) = menu_input.horizontal() else {
+        return;
+    };
+    let Some(ldtk_project) = ldtk_projects.get(&ldtk_handle.0) else {
+        return;
+    };
+    let count = ldtk_project.iter_raw_levels().count() as isize;
+    if count == 0 {
+        return;
+    }
+    let current = match level_selection.as_ref() {
+        LevelSelection::Indices(indices) => indices.level as isize,
+        _ => 0,
+    };
+    *level_selection = LevelSelection::index((current + step).rem_euclid(count) as usize);
+
+    // rebuild the screen to show the new name
+    for entity in screen_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    let name = stage_name(&level_selection, Some(ldtk_project)).unwrap_or_default();
+    spawn_stage_screen(&mut commands, &name);
+}
+
+/// Netplay cannot pause, as the other peer would keep simulating.
+fn toggle_pause(
+    state: Res<State<GameState>>,
+    mut next_state: ResMut<NextState<GameState>>,
+    menu_input: MenuInput,
+    session: Option<Res<RollbackSession>>,
+) {
+    if !menu_input.pause() || session.is_some() {
+        return;
+    }
+    next_state.set(match state.get() {
+        GameState::Paused => GameState::Fight,
+        _ => GameState::Paused,
+    });
+}
+
+fn spawn_pause_screen(mut commands: Commands) {
+    spawn_screen(
+        &mut commands,
+        GameState::Paused,
+        &[
+            String::from("Paused"),
+            String::from("Press Escape or Start to resume"),
+        ],
+        true,
+    );
+}
+
+/// Shown over the fight during the pause after a round. The pause is part of the
+/// simulation, so a rollback may take it back again.
+fn show_round_over(
+    mut commands: Commands,
+    match_state: Res<MatchState>,
+    screen_query: Query<Entity, With<RoundOverScreen>>,
+) {
+    if match_state.round_over.is_none() {
+        for entity in screen_query.iter() {
+            commands.entity(entity).despawn_recursive();
+        }
+        return;
+    }
+    if !screen_query.is_empty() {
+        return;
+    }
+    let Some(result) = match_state.results.last() else {
+        return;
+    };
+    let headline = match result.end {
+        RoundEnd::KnockOut => "K.O.",
+        RoundEnd::TimeOut => "Time",
+    };
+    let winner = match result.winner {
+        Some(player_id) => format!("Player {} wins round {}", player_id.0 + 1, result.round),
+        None => format!("Round {} is a draw", result.round),
+    };
+    let screen = spawn_screen(
+        &mut commands,
+        GameState::Fight,
+        &[String::from(headline), winner],
+        true,
+    );
+    commands.entity(screen).insert(RoundOverScreen);
+}
+
+fn spawn_results(mut commands: Commands, match_state: Res<MatchState>) {
+    let headline = match match_state.outcome {
+        Some(MatchOutcome::Winner(player_id)) => format!("Player {} wins", player_id.0 + 1),
+        _ => String::from("Draw"),
+    };
+    let score = match_state
+        .wins
+        .iter()
+        .map(|wins| wins.to_string())
+        .collect::<Vec<_>>()
+        .join(" - ");
+    spawn_screen(
+        &mut commands,
+        GameState::MatchOver,
+        &[headline, score, String::from("Press Enter or Start")],
+        false,
+    );
+}
@@ -0,0 +1,93 @@
+use crate::bindings::InputBindings;
+use crate::movement::{InputSet, PendingInput, PlayerInput};
+use crate::player::{PlayerId, MAX_PLAYERS};
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+/// sin(22.5°): splits the stick into eight equal direction sectors.
+const STICK_DIRECTION_THRESHOLD: f32 = 0.383;
+
+/// Which gamepad drives which player slot. A slot keeps its pad until that pad disconnects.
+#[derive(Resource, Default, Debug)]
+pub struct GamepadAssignments(pub HashMap<PlayerId, Gamepad>);
+
+pub struct GamepadInputPlugin;
+
+impl Plugin for GamepadInputPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GamepadAssignments>().add_systems(
+            FixedUpdate,
+            (assign_gamepads, handle_gamepad_input)
+                .chain()
+                .in_set(InputSet::Collect),
+        );
+    }
+}
+
+fn assign_gamepads(gamepads: Res<Gamepads>, mut assignments: ResMut<GamepadAssignments>) {
+    assignments.0.retain(|player, gamepad| {
+        let connected = gamepads.contains(*gamepad);
+        if !connected {
+            info!("{:?} disconnected from {:?}", gamepad, player);
+        }
+        connected
+    });
+
+    // lower ids connected first, so the first pad plugged in becomes player one
+    let mut unassigned: Vec<Gamepad> = gamepads
+        .iter()
+        .filter(|gamepad| !assignments.0.values().any(|assigned| assigned == gamepad))
+        .collect();
+    unassigned.sort_by_key(|gamepad| gamepad.id);
+    for gamepad in unassigned {
+        let Some(player) = (0..MAX_PLAYERS)
+            .map(PlayerId)
+            .find(|player| !assignments.0.contains_key(player))
+        else {
+            break;
+        };
+        info!("{:?} assigned to {:?}", gamepad, player);
+        assignments.0.insert(player, gamepad);
+    }
+}
+
+fn handle_gamepad_input(
+    assignments: Res<GamepadAssignments>,
+    bindings: Res<InputBindings>,
+    buttons: Res<Input<GamepadButton>>,
+    axes: Res<Axis<GamepadAxis>>,
+    mut pending: ResMut<PendingInput>,
+) {
+    for (player, gamepad) in assignments.0.iter() {
+        let Some(player_bindings) = bindings.player(*player) else {
+            continue;
+        };
+        let input = pending.0.entry(*player).or_default();
+        for (button_type, action) in player_bindings.buttons.iter() {
+            if buttons.pressed(GamepadButton::new(*gamepad, *button_type)) {
+                input.insert(*action);
+            }
+        }
+
+        let stick = Vec2::new(
+            axes.get(GamepadAxis::new(*gamepad, GamepadAxisType::LeftStickX))
+                .unwrap_or(0.0),
+            axes.get(GamepadAxis::new(*gamepad, GamepadAxisType::LeftStickY))
+                .unwrap_or(0.0),
+        );
+        if stick.length() < bindings.stick_deadzone {
+            continue;
+        }
+        let direction = stick.normalize();
+        if direction.x < -STICK_DIRECTION_THRESHOLD {
+            input.insert(PlayerInput::Left);
+        } else if direction.x > STICK_DIRECTION_THRESHOLD {
+            input.insert(PlayerInput::Right);
+        }
+        if direction.y > STICK_DIRECTION_THRESHOLD {
+            input.insert(PlayerInput::Up);
+        } else if direction.y < -STICK_DIRECTION_THRESHOLD {
+            input.insert(PlayerInput::Down);
+        }
+    }
+}
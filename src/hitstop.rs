@@ -0,0 +1,107 @@
+use crate::attack::{attack_hit, PlayerBlockEvent, PlayerHitEvent};
+use crate::game_state::GameState;
+use crate::player::{apply_velocity, Player};
+use crate::replay::Replay;
+use crate::rollback::{RollbackAppExt, RollbackSession};
+use crate::simulation::GameSet;
+use bevy::prelude::*;
+
+/// Accessibility setting that turns off hitstop and screen shake. Enabled with
+/// `--reduced-motion`, toggled with F3 outside of fights.
+///
+/// Hitstop is part of the simulation, so this is recorded in replays and agreed on by
+/// netplay peers, and cannot change while either depends on it.
+#[derive(Resource, Default, Copy, Clone, Debug)]
+pub struct ReducedMotion(pub bool);
+
+const REDUCED_MOTION_KEY: KeyCode = KeyCode::F3;
+
+/// Simulation ticks a player stays frozen after an attack connects. Frozen players keep
+/// their state, timers and any knockback until it runs out.
+#[derive(Component, Copy, Clone, Debug, Default)]
+pub struct Hitstop(pub u32);
+
+impl Hitstop {
+    pub fn frozen(&self) -> bool {
+        self.0 > 0
+    }
+}
+
+pub struct HitstopPlugin;
+
+impl Plugin for HitstopPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ReducedMotion(
+            std::env::args().any(|arg| arg == "--reduced-motion"),
+        ))
+        .rollback_component::<Hitstop>()
+        .add_systems(Update, toggle_reduced_motion)
+        .add_systems(
+            FixedUpdate,
+            start_hitstop.after(attack_hit).in_set(GameSet::Combat),
+        )
+        .add_systems(
+            FixedUpdate,
+            tick_hitstop.after(apply_velocity).in_set(GameSet::Movement),
+        );
+    }
+}
+
+fn toggle_reduced_motion(
+    keyboard_input: Res<Input<KeyCode>>,
+    state: Res<State<GameState>>,
+    replay: Res<Replay>,
+    session: Option<Res<RollbackSession>>,
+    mut reduced_motion: ResMut<ReducedMotion>,
+) {
+    if !keyboard_input.just_pressed(REDUCED_MOTION_KEY) {
+        return;
+    }
+    if *state.get() == GameState::Fight
+        || !matches!(replay.as_ref(), Replay::Idle)
+        || session.is_some()
+    {
+        info!("reduced motion can only be changed outside of fights, replays and netplay");
+        return;
+    }
+    reduced_motion.0 = !reduced_motion.0;
+    info!(
+        "reduced motion {}",
+        if reduced_motion.0 { "on" } else { "off" }
+    );
+}
+
+/// Freezes attacker and victim alike, so neither gains frames from the pause.
+fn start_hitstop(
+    reduced_motion: Res<ReducedMotion>,
+    mut query: Query<&mut Hitstop, With<Player>>,
+    mut ev_hit: EventReader<PlayerHitEvent>,
+    mut ev_block: EventReader<PlayerBlockEvent>,
+) {
+    let contacts = ev_hit
+        .read()
+        .map(|hit| (hit.attacker, hit.victim, hit.hitstop))
+        .chain(
+            ev_block
+                .read()
+                .map(|block| (block.attacker, block.defender, block.hitstop)),
+        )
+        .collect::<Vec<_>>();
+    if reduced_motion.0 {
+        return;
+    }
+    for (attacker, defender, frames) in contacts {
+        for entity in [attacker, defender] {
+            if let Ok(mut hitstop) = query.get_mut(entity) {
+                hitstop.0 = hitstop.0.max(frames);
+            }
+        }
+    }
+}
+
+/// Runs after movement, so the tick a hit lands on already counts as frozen.
+fn tick_hitstop(mut query: Query<&mut Hitstop, With<Player>>) {
+    for mut hitstop in query.iter_mut() {
+        hitstop.0 = hitstop.0.saturating_sub(1);
+    }
+}
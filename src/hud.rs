@@ -0,0 +1,343 @@
+use crate::game_state::GameState;
+use crate::player::{Health, Meter, Player, PlayerId, MAX_PLAYERS};
+use crate::rounds::{MatchRules, MatchState};
+use bevy::prelude::*;
+use bevy::window::{PrimaryWindow, WindowResized};
+
+/// Window height the HUD is laid out for; other heights scale it.
+const REFERENCE_HEIGHT: f32 = 720.0;
+/// How long recoverable damage stays on the bar before it starts to drain.
+const DRAIN_DELAY_SECONDS: f32 = 0.8;
+/// Fraction of the whole bar drained per second once the delay is over.
+const DRAIN_PER_SECOND: f32 = 0.5;
+
+const HEALTH_COLOR: Color = Color::rgb(0.95, 0.8, 0.1);
+const RECOVERABLE_COLOR: Color = Color::rgb(0.8, 0.1, 0.1);
+const METER_COLOR: Color = Color::rgb(0.2, 0.6, 1.0);
+const BAR_BACKGROUND: Color = Color::rgba(0.0, 0.0, 0.0, 0.6);
+const PIP_EMPTY: Color = Color::rgba(1.0, 1.0, 1.0, 0.2);
+const PIP_WON: Color = Color::rgb(1.0, 0.85, 0.2);
+
+/// Root of the HUD. It only mirrors gameplay state, so it lives from the first fight of a
+/// match until the results screen is left.
+#[derive(Component)]
+struct Hud;
+
+#[derive(Component)]
+struct HealthBar(PlayerId);
+
+/// The part of the health bar that trails behind recent damage before draining away.
+#[derive(Component)]
+struct RecoverableBar {
+    player: PlayerId,
+    shown: f32,
+    /// Health last frame; any new damage restarts the delay.
+    last: f32,
+    delay: Timer,
+}
+
+#[derive(Component)]
+struct MeterBar(PlayerId);
+
+#[derive(Component)]
+struct RoundPip {
+    player: PlayerId,
+    index: u32,
+}
+
+#[derive(Component)]
+struct RoundTimerText;
+
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(GameState::Fight), spawn_hud)
+            .add_systems(OnExit(GameState::MatchOver), despawn_hud)
+            .add_systems(
+                Update,
+                (
+                    scale_to_window,
+                    update_health_bars,
+                    update_meter_bars,
+                    update_round_timer,
+                    update_round_pips,
+                ),
+            );
+    }
+}
+
+/// Player slots alternate sides, and everything on the right is mirrored.
+fn is_right_side(player: PlayerId) -> bool {
+    player.0 % 2 == 1
+}
+
+/// Absolutely positioned fill of a bar, anchored to the screen edge of its side.
+fn fill_bundle(right_side: bool, color: Color) -> NodeBundle {
+    NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            width: Val::Percent(100.0),
+            height: Val::Percent(100.0),
+            left: if right_side { Val::Auto } else { Val::Px(0.0) },
+            right: if right_side { Val::Px(0.0) } else { Val::Auto },
+            ..default()
+        },
+        background_color: color.into(),
+        ..default()
+    }
+}
+
+fn bar_bundle(height: f32) -> NodeBundle {
+    NodeBundle {
+        style: Style {
+            width: Val::Percent(100.0),
+            height: Val::Px(height),
+            ..default()
+        },
+        background_color: BAR_BACKGROUND.into(),
+        ..default()
+    }
+}
+
+fn spawn_player_panel(parent: &mut ChildBuilder, player: PlayerId, rounds_to_win: u32) {
+    let right_side = is_right_side(player);
+    let (align, row) = if right_side {
+        (AlignItems::FlexEnd, FlexDirection::RowReverse)
+    } else {
+        (AlignItems::FlexStart, FlexDirection::Row)
+    };
+    parent
+        .spawn(NodeBundle {
+            style: Style {
+                width: Val::Percent(40.0),
+                flex_direction: FlexDirection::Column,
+                align_items: align,
+                row_gap: Val::Px(4.0),
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|panel| {
+            panel.spawn(bar_bundle(20.0)).with_children(|bar| {
+                bar.spawn((
+                    fill_bundle(right_side, RECOVERABLE_COLOR),
+                    RecoverableBar {
+                        player,
+                        shown: 1.0,
+                        last: 1.0,
+                        delay: Timer::from_seconds(DRAIN_DELAY_SECONDS, TimerMode::Once),
+                    },
+                ));
+                bar.spawn((fill_bundle(right_side, HEALTH_COLOR), HealthBar(player)));
+            });
+            panel
+                .spawn(NodeBundle {
+                    style: Style {
+                        flex_direction: row,
+                        column_gap: Val::Px(8.0),
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .with_children(|row| {
+                    row.spawn(TextBundle::from_section(
+                        format!("Player {}", player.0 + 1),
+                        TextStyle {
+                            font_size: 18.0,
+                            color: Color::WHITE,
+                            ..default()
+                        },
+                    ));
+                    for index in 0..rounds_to_win {
+                        row.spawn((
+                            NodeBundle {
+                                style: Style {
+                                    width: Val::Px(10.0),
+                                    height: Val::Px(10.0),
+                                    ..default()
+                                },
+                                background_color: PIP_EMPTY.into(),
+                                ..default()
+                            },
+                            RoundPip { player, index },
+                        ));
+                    }
+                });
+        });
+}
+
+fn spawn_meter_panel(parent: &mut ChildBuilder, player: PlayerId) {
+    let right_side = is_right_side(player);
+    parent
+        .spawn(NodeBundle {
+            style: Style {
+                width: Val::Percent(25.0),
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|panel| {
+            panel.spawn(bar_bundle(10.0)).with_children(|bar| {
+                let mut fill = fill_bundle(right_side, METER_COLOR);
+                fill.style.width = Val::Percent(0.0);
+                bar.spawn((fill, MeterBar(player)));
+            });
+        });
+}
+
+fn spawn_hud(mut commands: Commands, rules: Res<MatchRules>, hud_query: Query<(), With<Hud>>) {
+    // resuming from a pause or starting the next round keeps the existing HUD
+    if !hud_query.is_empty() {
+        return;
+    }
+    let players: Vec<PlayerId> = (0..MAX_PLAYERS).map(PlayerId).collect();
+    let row_style = Style {
+        width: Val::Percent(100.0),
+        justify_content: JustifyContent::SpaceBetween,
+        align_items: AlignItems::FlexStart,
+        ..default()
+    };
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    flex_direction: FlexDirection::Column,
+                    justify_content: JustifyContent::SpaceBetween,
+                    padding: UiRect::all(Val::Px(16.0)),
+                    ..default()
+                },
+                ..default()
+            },
+            Hud,
+        ))
+        .with_children(|hud| {
+            hud.spawn(NodeBundle {
+                style: row_style.clone(),
+                ..default()
+            })
+            .with_children(|top| {
+                let (left, right) = players.split_at(players.len() / 2);
+                for player in left {
+                    spawn_player_panel(top, *player, rules.rounds_to_win());
+                }
+                top.spawn((
+                    TextBundle::from_section(
+                        "",
+                        TextStyle {
+                            font_size: 40.0,
+                            color: Color::WHITE,
+                            ..default()
+                        },
+                    ),
+                    RoundTimerText,
+                ));
+                for player in right {
+                    spawn_player_panel(top, *player, rules.rounds_to_win());
+                }
+            });
+            hud.spawn(NodeBundle {
+                style: row_style,
+                ..default()
+            })
+            .with_children(|bottom| {
+                for player in players.iter() {
+                    spawn_meter_panel(bottom, *player);
+                }
+            });
+        });
+}
+
+fn despawn_hud(mut commands: Commands, hud_query: Query<Entity, With<Hud>>) {
+    for entity in hud_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+/// Keeps the HUD the same size relative to the window; percentages handle the rest.
+fn scale_to_window(
+    mut ev_resized: EventReader<WindowResized>,
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    mut ui_scale: ResMut<UiScale>,
+) {
+    if ev_resized.read().count() == 0 && !ui_scale.is_added() {
+        return;
+    }
+    if let Ok(window) = window_query.get_single() {
+        ui_scale.0 = (window.height() / REFERENCE_HEIGHT).max(0.25) as f64;
+    }
+}
+
+fn health_fraction(health: &Health) -> f32 {
+    (health.current / health.max).clamp(0.0, 1.0)
+}
+
+fn update_health_bars(
+    time: Res<Time>,
+    player_query: Query<(&PlayerId, &Health), With<Player>>,
+    mut health_query: Query<(&HealthBar, &mut Style), Without<RecoverableBar>>,
+    mut recoverable_query: Query<(&mut RecoverableBar, &mut Style), Without<HealthBar>>,
+) {
+    let fraction_of = |player: PlayerId| {
+        player_query
+            .iter()
+            .find(|(player_id, _)| **player_id == player)
+            .map(|(_, health)| health_fraction(health))
+    };
+    for (bar, mut style) in health_query.iter_mut() {
+        if let Some(fraction) = fraction_of(bar.0) {
+            style.width = Val::Percent(fraction * 100.0);
+        }
+    }
+    for (mut bar, mut style) in recoverable_query.iter_mut() {
+        let Some(fraction) = fraction_of(bar.player) else {
+            continue;
+        };
+        if fraction >= bar.shown {
+            // healed, e.g. by a new round
+            bar.shown = fraction;
+        } else if fraction < bar.last {
+            bar.delay.reset();
+        } else if bar.delay.tick(time.delta()).finished() {
+            bar.shown = (bar.shown - DRAIN_PER_SECOND * time.delta_seconds()).max(fraction);
+        }
+        bar.last = fraction;
+        style.width = Val::Percent(bar.shown * 100.0);
+    }
+}
+
+fn update_meter_bars(
+    player_query: Query<(&PlayerId, &Meter), With<Player>>,
+    mut bar_query: Query<(&MeterBar, &mut Style)>,
+) {
+    for (bar, mut style) in bar_query.iter_mut() {
+        if let Some((_, meter)) = player_query
+            .iter()
+            .find(|(player_id, _)| **player_id == bar.0)
+        {
+            style.width = Val::Percent((meter.current / meter.max).clamp(0.0, 1.0) * 100.0);
+        }
+    }
+}
+
+fn update_round_timer(
+    match_state: Res<MatchState>,
+    mut text_query: Query<&mut Text, With<RoundTimerText>>,
+) {
+    for mut text in text_query.iter_mut() {
+        text.sections[0].value = match_state.seconds_left().to_string();
+    }
+}
+
+fn update_round_pips(
+    match_state: Res<MatchState>,
+    mut pip_query: Query<(&RoundPip, &mut BackgroundColor)>,
+) {
+    for (pip, mut color) in pip_query.iter_mut() {
+        let won = match_state.wins.get(pip.player.0).copied().unwrap_or(0);
+        color.0 = if pip.index < won { PIP_WON } else { PIP_EMPTY };
+    }
+}
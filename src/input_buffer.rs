@@ -0,0 +1,110 @@
+use crate::hitstop::Hitstop;
+use crate::movement::{InputState, PlayerInput, Velocity};
+use crate::player::Player;
+use crate::player_state::update_player_state;
+use crate::rollback::RollbackAppExt;
+use crate::simulation::GameSet;
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// How long presses are remembered, in simulation ticks. Set with `--input-buffer <frames>`.
+/// Part of the simulation, so recorded in replays and agreed on by netplay peers.
+#[derive(Resource, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BufferSettings {
+    /// An attack or jump pressed while it cannot happen yet comes out if it becomes
+    /// possible within this many ticks.
+    pub buffer_frames: u32,
+    /// Ticks after walking off a ledge during which a jump still counts as grounded.
+    pub coyote_frames: u32,
+}
+
+impl Default for BufferSettings {
+    fn default() -> Self {
+        Self {
+            buffer_frames: 6,
+            coyote_frames: 5,
+        }
+    }
+}
+
+/// Presses a player is still waiting to act on.
+#[derive(Component, Clone, Debug, Default)]
+pub struct InputBuffer {
+    /// Ticks left on a buffered attack press.
+    pub attack: u32,
+    /// Ticks left on a buffered jump press.
+    pub jump: u32,
+    /// Ticks left to jump after leaving the ground without jumping.
+    pub coyote: u32,
+    /// Ticks left on a buffered attack release, which performs charge moves.
+    pub release: u32,
+    /// How long attack was held before that release.
+    pub charged: u32,
+}
+
+pub struct InputBufferPlugin;
+
+impl Plugin for InputBufferPlugin {
+    fn build(&self, app: &mut App) {
+        let args: Vec<String> = std::env::args().collect();
+        let defaults = BufferSettings::default();
+        let buffer_frames = args
+            .iter()
+            .position(|arg| arg == "--input-buffer")
+            .and_then(|index| args.get(index + 1))
+            .and_then(|value| value.parse::<u32>().ok())
+            .unwrap_or(defaults.buffer_frames);
+
+        app.insert_resource(BufferSettings {
+            buffer_frames,
+            ..defaults
+        })
+        .rollback_component::<InputBuffer>()
+        .add_systems(
+            FixedUpdate,
+            buffer_inputs
+                .before(update_player_state)
+                .in_set(GameSet::Player),
+        );
+    }
+}
+
+/// What a player's buffer is counted down from.
+type BufferedPlayer<'a> = (
+    &'a InputState,
+    &'a Hitstop,
+    &'a Velocity,
+    &'a KinematicCharacterControllerOutput,
+    &'a mut InputBuffer,
+);
+
+/// Starts the buffer of every new attack and jump press, and of attack releases, and counts
+/// the rest down. Frozen players keep what they pressed until hitstop is over.
+fn buffer_inputs(settings: Res<BufferSettings>, mut query: Query<BufferedPlayer, With<Player>>) {
+    for (input, hitstop, velocity, controller, mut buffer) in query.iter_mut() {
+        if !hitstop.frozen() {
+            buffer.attack = buffer.attack.saturating_sub(1);
+            buffer.jump = buffer.jump.saturating_sub(1);
+            buffer.release = buffer.release.saturating_sub(1);
+            // rising off the ground is a jump, not a ledge
+            if controller.grounded && velocity.velocity.y <= 0.0 {
+                buffer.coyote = settings.coyote_frames;
+            } else {
+                buffer.coyote = buffer.coyote.saturating_sub(1);
+            }
+        }
+
+        // a press always lasts at least the tick it happened on
+        if input.just_pressed(PlayerInput::Attack) {
+            buffer.attack = settings.buffer_frames.max(1);
+        }
+        if input.just_pressed(PlayerInput::Up) {
+            buffer.jump = settings.buffer_frames.max(1);
+        }
+        if let Some(held) = input.just_released(PlayerInput::Attack) {
+            buffer.release = settings.buffer_frames.max(1);
+            buffer.charged = held;
+        }
+    }
+}
@@ -1,22 +1,59 @@
 use bevy::{prelude::*, window::PresentMode};
 use bevy_rapier2d::prelude::*;
 
+mod animation;
 mod attack;
+mod bindings;
 mod camera;
+mod character;
+mod collision;
+mod debug_overlay;
+mod game_state;
+mod gamepad;
+mod hitstop;
+mod hud;
+mod input_buffer;
+mod motion;
 mod movement;
+mod moves;
 mod player;
+mod player_state;
+mod replay;
+mod rollback;
+mod rounds;
+mod simulation;
+mod transport;
 mod world;
 
+use animation::AnimationPlugin;
 use attack::AttackPlugin;
+use attack::PlayerBlockEvent;
+use attack::PlayerHitEvent;
+use bindings::BindingsPlugin;
 use camera::CameraPlugin;
+use character::CharacterPlugin;
+use collision::CollisionPlugin;
+use debug_overlay::DebugOverlayPlugin;
+use game_state::GameStatePlugin;
+use gamepad::GamepadInputPlugin;
+use hitstop::HitstopPlugin;
+use hud::HudPlugin;
+use input_buffer::InputBufferPlugin;
+use motion::MotionPlugin;
 use movement::MovementPlugin;
-use movement::PlayerInputEvent;
+use moves::MovesPlugin;
 use player::PlayerPlugin;
+use player_state::PlayerStatePlugin;
+use replay::ReplayPlugin;
+use rollback::RollbackPlugin;
+use rounds::RoundsPlugin;
+use simulation::SimulationPlugin;
 use world::WorldPlugin;
 
 fn main() {
     App::new()
-        .add_event::<PlayerInputEvent>()
+        .add_event::<PlayerHitEvent>()
+        .add_event::<PlayerBlockEvent>()
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
                 title: String::from("Fight Game"),
@@ -25,12 +62,35 @@ fn main() {
             }),
             ..default()
         }))
-        .add_plugins(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0))
-        .add_plugins(RapierDebugRenderPlugin::default())
+        .add_plugins(
+            RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0)
+                .with_default_system_setup(false),
+        )
+        .add_plugins(RapierDebugRenderPlugin {
+            enabled: false,
+            ..default()
+        })
+        .add_plugins(GameStatePlugin)
+        .add_plugins(SimulationPlugin)
         .add_plugins(CameraPlugin)
         .add_plugins(WorldPlugin)
+        .add_plugins(BindingsPlugin)
         .add_plugins(MovementPlugin)
+        .add_plugins(MovesPlugin)
+        .add_plugins(MotionPlugin)
+        .add_plugins(InputBufferPlugin)
+        .add_plugins(CharacterPlugin)
+        .add_plugins(GamepadInputPlugin)
         .add_plugins(PlayerPlugin)
+        .add_plugins(PlayerStatePlugin)
         .add_plugins(AttackPlugin)
+        .add_plugins(CollisionPlugin)
+        .add_plugins(HitstopPlugin)
+        .add_plugins(AnimationPlugin)
+        .add_plugins(DebugOverlayPlugin)
+        .add_plugins(RoundsPlugin)
+        .add_plugins(HudPlugin)
+        .add_plugins(ReplayPlugin)
+        .add_plugins(RollbackPlugin)
         .run();
 }
@@ -0,0 +1,360 @@
+use crate::movement::{InputState, PlayerInput};
+use crate::player::{Direction, Player};
+use crate::player_state::update_player_state;
+use crate::rollback::RollbackAppExt;
+use crate::simulation::GameSet;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+
+/// Ticks of directions kept per player; enough for the longest charge.
+const HISTORY_FRAMES: usize = 90;
+
+/// A special input, written in numpad notation relative to facing: 6 is forward, 4 back,
+/// 2 down. Declared from most to least specific, so when several complete on the same
+/// tick the first one wins.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum MotionCommand {
+    /// 41236
+    HalfCircleForward,
+    /// 623
+    DragonPunch,
+    /// Back held for `charge_frames`, then forward.
+    ChargeBackForward,
+    /// 236
+    QuarterCircleForward,
+    /// 214
+    QuarterCircleBack,
+    /// 656
+    DashForward,
+    /// 454
+    DashBack,
+}
+
+impl MotionCommand {
+    pub const ALL: [MotionCommand; 7] = [
+        Self::HalfCircleForward,
+        Self::DragonPunch,
+        Self::ChargeBackForward,
+        Self::QuarterCircleForward,
+        Self::QuarterCircleBack,
+        Self::DashForward,
+        Self::DashBack,
+    ];
+
+    /// Entering this motion also enters `other`, the way 41236 ends in 236.
+    fn contains(self, other: MotionCommand) -> bool {
+        if other == Self::ChargeBackForward {
+            return false;
+        }
+        let mut directions = self.sequence().iter();
+        other
+            .sequence()
+            .iter()
+            .all(|direction| directions.any(|candidate| candidate == direction))
+    }
+
+    fn sequence(self) -> &'static [u8] {
+        match self {
+            Self::HalfCircleForward => &[4, 1, 2, 3, 6],
+            Self::DragonPunch => &[6, 2, 3],
+            Self::ChargeBackForward => &[6],
+            Self::QuarterCircleForward => &[2, 3, 6],
+            Self::QuarterCircleBack => &[2, 1, 4],
+            Self::DashForward => &[6, 5, 6],
+            Self::DashBack => &[4, 5, 4],
+        }
+    }
+}
+
+/// How sloppy a motion may be, in simulation ticks. Part of the simulation, so recorded in
+/// replays and agreed on by netplay peers.
+#[derive(Resource, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MotionLeniency {
+    /// Longest a directional motion may take from its first direction to its last.
+    pub motion_frames: u32,
+    /// How long a completed motion waits for the button that performs it.
+    pub button_frames: u32,
+    /// How long back has to be held to charge.
+    pub charge_frames: u32,
+    /// How late forward may come after back is let go.
+    pub charge_release_frames: u32,
+    /// Longest gap between the two taps of a dash.
+    pub dash_frames: u32,
+}
+
+impl Default for MotionLeniency {
+    fn default() -> Self {
+        Self {
+            motion_frames: 15,
+            button_frames: 8,
+            charge_frames: 40,
+            charge_release_frames: 8,
+            dash_frames: 12,
+        }
+    }
+}
+
+/// A player's recent directions, and the motions they completed.
+#[derive(Component, Clone, Debug, Default)]
+pub struct InputHistory {
+    /// Numpad direction of every tick relative to the facing at the time, oldest first.
+    pub directions: VecDeque<u8>,
+    /// Motions completed within `button_frames`, with how many ticks ago.
+    pub recent: Vec<(MotionCommand, u32)>,
+}
+
+impl InputHistory {
+    pub fn completed(&self, command: MotionCommand) -> bool {
+        self.recent.iter().any(|(recent, _)| *recent == command)
+    }
+
+    /// Forgets a motion once a move has used it, so one motion performs one move. Motions
+    /// completed on the same tick or entered along with it go too, so the 236 inside a
+    /// 41236 cannot perform a second move.
+    pub fn consume(&mut self, command: MotionCommand) {
+        let Some(age) = self
+            .recent
+            .iter()
+            .find(|(recent, _)| *recent == command)
+            .map(|(_, age)| *age)
+        else {
+            return;
+        };
+        self.recent.retain(|(recent, recent_age)| {
+            *recent != command && *recent_age != age && !command.contains(*recent)
+        });
+    }
+
+    /// The direction was entered this tick, rather than held from before.
+    fn entered(&self, matches: impl Fn(u8) -> bool) -> bool {
+        let mut latest = self.directions.iter().rev();
+        match (latest.next(), latest.next()) {
+            (Some(current), Some(previous)) => matches(*current) && !matches(*previous),
+            (Some(current), None) => matches(*current),
+            _ => false,
+        }
+    }
+
+    /// `sequence` appears, in order, within the last `window` ticks and ends this tick.
+    /// Directions in between are ignored.
+    fn matches_sequence(&self, sequence: &[u8], window: u32) -> bool {
+        let Some(last) = sequence.last() else {
+            return false;
+        };
+        if !self.entered(|direction| direction == *last) {
+            return false;
+        }
+        let mut remaining = sequence.iter().rev().peekable();
+        for direction in self.directions.iter().rev().take(window as usize) {
+            if remaining.peek() == Some(&direction) {
+                remaining.next();
+            }
+            if remaining.peek().is_none() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Forward entered this tick, shortly after back was held long enough.
+    fn charged(&self, leniency: &MotionLeniency) -> bool {
+        let is_back = |direction: u8| direction % 3 == 1;
+        let is_forward = |direction: u8| direction.is_multiple_of(3);
+        if !self.entered(is_forward) {
+            return false;
+        }
+        let earlier: Vec<u8> = self.directions.iter().rev().skip(1).copied().collect();
+        let Some(released) = earlier.iter().position(|direction| is_back(*direction)) else {
+            return false;
+        };
+        let held = earlier[released..]
+            .iter()
+            .take_while(|direction| is_back(**direction))
+            .count();
+        released <= leniency.charge_release_frames as usize
+            && held >= leniency.charge_frames as usize
+    }
+
+    fn detects(&self, command: MotionCommand, leniency: &MotionLeniency) -> bool {
+        match command {
+            MotionCommand::ChargeBackForward => self.charged(leniency),
+            MotionCommand::DashForward | MotionCommand::DashBack => {
+                self.matches_sequence(command.sequence(), leniency.dash_frames)
+            }
+            _ => self.matches_sequence(command.sequence(), leniency.motion_frames),
+        }
+    }
+}
+
+pub struct MotionPlugin;
+
+impl Plugin for MotionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MotionLeniency>()
+            .rollback_component::<InputHistory>()
+            .add_systems(
+                FixedUpdate,
+                read_motions
+                    .before(update_player_state)
+                    .in_set(GameSet::Player),
+            );
+    }
+}
+
+/// Numpad notation of the held directions: 5 is neutral, 6 toward `facing`, 8 up.
+fn numpad_direction(input: &HashSet<PlayerInput>, facing: Direction) -> u8 {
+    let (forward, back) = match facing {
+        Direction::Left => (PlayerInput::Left, PlayerInput::Right),
+        Direction::Right => (PlayerInput::Right, PlayerInput::Left),
+    };
+    let axis = |positive: PlayerInput, negative: PlayerInput| match (
+        input.contains(&positive),
+        input.contains(&negative),
+    ) {
+        (true, false) => 1,
+        (false, true) => -1,
+        _ => 0,
+    };
+    (5 + axis(forward, back) + 3 * axis(PlayerInput::Up, PlayerInput::Down)) as u8
+}
+
+/// Adds this tick's direction to every player's history and looks for motions ending on it.
+/// Runs after the players have turned to face their opponent.
+pub fn read_motions(
+    leniency: Res<MotionLeniency>,
+    mut query: Query<(&Direction, &InputState, &mut InputHistory), With<Player>>,
+) {
+    for (direction, input, mut history) in query.iter_mut() {
+        history
+            .directions
+            .push_back(numpad_direction(&input.current, *direction));
+        if history.directions.len() > HISTORY_FRAMES {
+            history.directions.pop_front();
+        }
+
+        history.recent.retain_mut(|(_, age)| {
+            *age += 1;
+            *age <= leniency.button_frames
+        });
+        for command in MotionCommand::ALL {
+            if history.detects(command, &leniency) {
+                history.recent.push((command, 0));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(directions: &[u8]) -> InputHistory {
+        InputHistory {
+            directions: directions.iter().copied().collect(),
+            recent: Vec::new(),
+        }
+    }
+
+    fn held(inputs: &[PlayerInput]) -> HashSet<PlayerInput> {
+        inputs.iter().copied().collect()
+    }
+
+    #[test]
+    fn matches_sequence_skips_directions_in_between() {
+        assert!(history(&[5, 2, 2, 3, 3, 6]).matches_sequence(&[2, 3, 6], 15));
+        assert!(history(&[2, 5, 3, 5, 6]).matches_sequence(&[2, 3, 6], 15));
+        assert!(!history(&[3, 2, 6]).matches_sequence(&[2, 3, 6], 15));
+    }
+
+    #[test]
+    fn matches_sequence_ends_on_a_new_direction() {
+        assert!(!history(&[2, 3, 6, 6]).matches_sequence(&[2, 3, 6], 15));
+    }
+
+    #[test]
+    fn matches_sequence_fits_in_the_window() {
+        let mut directions = vec![2, 3];
+        directions.extend([5; 14]);
+        directions.push(6);
+        assert!(!history(&directions).matches_sequence(&[2, 3, 6], 15));
+        assert!(history(&directions).matches_sequence(&[2, 3, 6], 17));
+    }
+
+    #[test]
+    fn charged_after_holding_back_long_enough() {
+        let leniency = MotionLeniency::default();
+        let mut directions = vec![4; 40];
+        directions.push(6);
+        assert!(history(&directions).charged(&leniency));
+        // down-back charges too
+        let mut directions = vec![1; 40];
+        directions.push(3);
+        assert!(history(&directions).charged(&leniency));
+        let mut directions = vec![4; 39];
+        directions.push(6);
+        assert!(!history(&directions).charged(&leniency));
+    }
+
+    #[test]
+    fn charged_allows_a_late_forward() {
+        let leniency = MotionLeniency::default();
+        let mut directions = vec![4; 40];
+        directions.extend([5; 8]);
+        directions.push(6);
+        assert!(history(&directions).charged(&leniency));
+        directions.insert(40, 5);
+        assert!(!history(&directions).charged(&leniency));
+    }
+
+    #[test]
+    fn numpad_direction_is_relative_to_facing() {
+        assert_eq!(numpad_direction(&held(&[]), Direction::Right), 5);
+        assert_eq!(
+            numpad_direction(&held(&[PlayerInput::Right]), Direction::Right),
+            6
+        );
+        assert_eq!(
+            numpad_direction(&held(&[PlayerInput::Right]), Direction::Left),
+            4
+        );
+        assert_eq!(
+            numpad_direction(
+                &held(&[PlayerInput::Down, PlayerInput::Left]),
+                Direction::Left
+            ),
+            3
+        );
+        assert_eq!(
+            numpad_direction(
+                &held(&[PlayerInput::Up, PlayerInput::Left]),
+                Direction::Right
+            ),
+            7
+        );
+        assert_eq!(
+            numpad_direction(
+                &held(&[PlayerInput::Left, PlayerInput::Right]),
+                Direction::Right
+            ),
+            5
+        );
+    }
+
+    #[test]
+    fn consume_drops_motions_entered_along_with_it() {
+        let leniency = MotionLeniency::default();
+        let mut history = history(&[4, 1, 2, 3, 6]);
+        for command in MotionCommand::ALL {
+            if history.detects(command, &leniency) {
+                history.recent.push((command, 0));
+            }
+        }
+        assert!(history.completed(MotionCommand::HalfCircleForward));
+        assert!(history.completed(MotionCommand::QuarterCircleForward));
+        history.recent.push((MotionCommand::DashBack, 3));
+
+        history.consume(MotionCommand::HalfCircleForward);
+        assert_eq!(history.recent, vec![(MotionCommand::DashBack, 3)]);
+    }
+}
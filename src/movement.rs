@@ -1,5 +1,10 @@
+use crate::bindings::InputBindings;
+use crate::player::{Player, PlayerId};
+use crate::rollback::RollbackAppExt;
+use crate::simulation::GameSet;
 use bevy::prelude::*;
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 #[derive(Component, Copy, Clone, Debug)]
 pub struct Velocity {
     pub velocity: Vec2,
@@ -13,7 +18,7 @@ impl Default for Velocity {
         }
     }
 }
-#[derive(Copy, Clone, Debug, PartialEq, Hash, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Hash, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum PlayerInput {
     Left,
     Right,
@@ -22,40 +27,166 @@ pub enum PlayerInput {
     Attack,
     ResetLevel,
 }
-#[derive(Event, Clone, Debug, PartialEq)]
-pub struct PlayerInputEvent(pub HashSet<PlayerInput>);
+/// A player's actions this tick and the one before, written once per tick before any
+/// gameplay system runs. Systems query this instead of reading input events, so every one
+/// of them sees the same input no matter how many players there are.
+///
+/// Written in `FixedUpdate` in `InputSet::Write` rather than in `PreUpdate`, so it advances
+/// exactly once per simulation tick even when a frame runs no tick or several, and a
+/// rollback or replay writes it from the same input the tick was first simulated with.
+#[derive(Component, Clone, Debug, Default)]
+pub struct InputState {
+    pub current: HashSet<PlayerInput>,
+    pub previous: HashSet<PlayerInput>,
+    /// Ticks each held action has been held for, counting this one.
+    pub held_frames: HashMap<PlayerInput, u32>,
+    /// Actions let go this tick, with how many ticks each had been held.
+    pub released: HashMap<PlayerInput, u32>,
+}
+
+impl InputState {
+    pub fn pressed(&self, action: PlayerInput) -> bool {
+        self.current.contains(&action)
+    }
+
+    /// Held this tick but not the one before.
+    pub fn just_pressed(&self, action: PlayerInput) -> bool {
+        self.current.contains(&action) && !self.previous.contains(&action)
+    }
+
+    /// Ticks `action` was held for if it was let go this tick.
+    pub fn just_released(&self, action: PlayerInput) -> Option<u32> {
+        self.released.get(&action).copied()
+    }
+
+    /// Ticks `action` has been held, or 0 when it is up.
+    pub fn held_for(&self, action: PlayerInput) -> u32 {
+        self.held_frames.get(&action).copied().unwrap_or(0)
+    }
+
+    /// Moves on to the next tick with `current` held.
+    pub fn advance(&mut self, current: HashSet<PlayerInput>) {
+        let held_frames: HashMap<PlayerInput, u32> = current
+            .iter()
+            .map(|action| (*action, self.held_for(*action) + 1))
+            .collect();
+        self.released = self
+            .held_frames
+            .drain()
+            .filter(|(action, _)| !current.contains(action))
+            .collect();
+        self.held_frames = held_frames;
+        self.previous = std::mem::replace(&mut self.current, current);
+    }
+}
+
+/// Each slot's input as of the last tick, which a player that respawns carries on from, so
+/// an action held across the respawn is not pressed again.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct LastInput(pub HashMap<PlayerId, InputState>);
+
+/// Actions gathered from every input source this tick, written to each player's
+/// `InputState`.
+#[derive(Resource, Default, Debug)]
+pub struct PendingInput(pub HashMap<PlayerId, HashSet<PlayerInput>>);
+
+#[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone)]
+pub enum InputSet {
+    Collect,
+    Write,
+}
 
 pub struct MovementPlugin;
 
 impl Plugin for MovementPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(PreUpdate, handle_keyboard_input);
+        app.init_resource::<PendingInput>()
+            .init_resource::<LastInput>()
+            .rollback_component::<Velocity>()
+            .rollback_component::<InputState>()
+            .rollback_resource::<LastInput>()
+            .configure_sets(
+                FixedUpdate,
+                (InputSet::Collect, InputSet::Write)
+                    .chain()
+                    .in_set(GameSet::Input),
+            )
+            .add_systems(FixedUpdate, handle_keyboard_input.in_set(InputSet::Collect))
+            .add_systems(FixedUpdate, write_input_state.in_set(InputSet::Write));
     }
 }
 
 fn handle_keyboard_input(
     keyboard_input: Res<Input<KeyCode>>,
-    mut ev_input: EventWriter<PlayerInputEvent>,
+    bindings: Res<InputBindings>,
+    mut pending: ResMut<PendingInput>,
 ) {
-    let mut input = HashSet::new();
-    if keyboard_input.pressed(KeyCode::A) {
-        input.insert(PlayerInput::Left);
-    }
-    if keyboard_input.pressed(KeyCode::D) {
-        input.insert(PlayerInput::Right);
-    }
-    if keyboard_input.pressed(KeyCode::W) {
-        input.insert(PlayerInput::Up);
+    for (slot, player_bindings) in bindings.players.iter().enumerate() {
+        let input = pending.0.entry(PlayerId(slot)).or_default();
+        for (key, action) in player_bindings.keys.iter() {
+            if keyboard_input.pressed(*key) {
+                input.insert(*action);
+            }
+        }
     }
-    if keyboard_input.pressed(KeyCode::S) {
-        input.insert(PlayerInput::Down);
+}
+
+fn write_input_state(
+    mut pending: ResMut<PendingInput>,
+    mut last_input: ResMut<LastInput>,
+    mut query: Query<(&PlayerId, &mut InputState), With<Player>>,
+) {
+    for (player_id, mut input) in query.iter_mut() {
+        if input.is_added() {
+            if let Some(last) = last_input.0.get(player_id) {
+                *input = last.clone();
+            }
+        }
+        input.advance(pending.0.remove(player_id).unwrap_or_default());
+        last_input.0.insert(*player_id, input.clone());
     }
-    if keyboard_input.pressed(KeyCode::Space) {
-        input.insert(PlayerInput::Attack);
+    // slots without a player this tick
+    pending.0.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advance(input: &mut InputState, actions: &[PlayerInput]) {
+        input.advance(actions.iter().copied().collect());
     }
-    if keyboard_input.pressed(KeyCode::R) {
-        input.insert(PlayerInput::ResetLevel);
+
+    #[test]
+    fn advance_counts_how_long_a_release_was_held() {
+        let mut input = InputState::default();
+        advance(&mut input, &[PlayerInput::Attack]);
+        assert!(input.just_pressed(PlayerInput::Attack));
+        assert_eq!(input.held_for(PlayerInput::Attack), 1);
+
+        advance(&mut input, &[PlayerInput::Attack]);
+        advance(&mut input, &[PlayerInput::Attack]);
+        assert!(!input.just_pressed(PlayerInput::Attack));
+        assert_eq!(input.held_for(PlayerInput::Attack), 3);
+        assert_eq!(input.just_released(PlayerInput::Attack), None);
+
+        advance(&mut input, &[]);
+        assert_eq!(input.just_released(PlayerInput::Attack), Some(3));
+        assert_eq!(input.held_for(PlayerInput::Attack), 0);
+
+        advance(&mut input, &[]);
+        assert_eq!(input.just_released(PlayerInput::Attack), None);
     }
 
-    ev_input.send(PlayerInputEvent(input));
+    #[test]
+    fn advance_presses_again_right_after_a_release() {
+        let mut input = InputState::default();
+        advance(&mut input, &[PlayerInput::Attack]);
+        advance(&mut input, &[PlayerInput::Attack]);
+        advance(&mut input, &[]);
+        advance(&mut input, &[PlayerInput::Attack]);
+        assert!(input.just_pressed(PlayerInput::Attack));
+        assert_eq!(input.held_for(PlayerInput::Attack), 1);
+        assert_eq!(input.just_released(PlayerInput::Attack), None);
+    }
 }
@@ -0,0 +1,352 @@
+use crate::motion::{InputHistory, MotionCommand};
+use crate::player::AttackHeight;
+use bevy::utils::thiserror;
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    reflect::TypePath,
+    utils::BoxedFuture,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// A rectangle relative to the player's center, as seen when facing right.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Hitbox {
+    pub offset: Vec2,
+    pub size: Vec2,
+}
+
+/// How an attack has to be guarded.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GuardType {
+    /// Blocked standing or crouching.
+    #[default]
+    Mid,
+    /// Blocked only crouching.
+    Low,
+    /// Blocked only standing.
+    Overhead,
+    Unblockable,
+}
+
+/// Frame data of a single attack. Frames are simulation ticks.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MoveDef {
+    pub startup: u32,
+    pub active: u32,
+    pub recovery: u32,
+    /// One list of boxes per active frame.
+    pub hitboxes: Vec<Vec<Hitbox>>,
+    pub damage: f32,
+    /// Damage dealt even when blocked.
+    #[serde(default)]
+    pub chip_damage: f32,
+    #[serde(default)]
+    pub guard: GuardType,
+    pub hitstun: u32,
+    pub blockstun: u32,
+    /// Pushes the victim away from the attacker; x is flipped when facing left.
+    pub knockback: Vec2,
+    /// Moves this one can be cancelled into once it has connected, after startup.
+    #[serde(default)]
+    pub cancels: Vec<String>,
+    /// Frames both players freeze for on contact; derived from damage when left out.
+    #[serde(default)]
+    pub hitstop: Option<u32>,
+    /// Camera trauma added on hit, from 0 to 1; derived from damage when left out.
+    #[serde(default)]
+    pub shake: Option<f32>,
+    /// Makes this a special move, performed by pressing attack right after the motion.
+    #[serde(default)]
+    pub motion: Option<MotionCommand>,
+    /// Makes this a charge move, performed by letting go of attack after holding it for at
+    /// least this many ticks.
+    #[serde(default)]
+    pub charge: Option<u32>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MovePhase {
+    Startup,
+    /// Index into `MoveDef::hitboxes`.
+    Active(usize),
+    Recovery,
+}
+
+impl MoveDef {
+    pub fn total_frames(&self) -> u32 {
+        self.startup + self.active + self.recovery
+    }
+
+    pub fn hitstop_frames(&self) -> u32 {
+        self.hitstop
+            .unwrap_or_else(|| 4 + (self.damage / 3.0).round() as u32)
+    }
+
+    pub fn shake_trauma(&self) -> f32 {
+        self.shake.unwrap_or(self.damage / 40.0).clamp(0.0, 1.0)
+    }
+
+    pub fn phase(&self, frame: u32) -> MovePhase {
+        if frame < self.startup {
+            MovePhase::Startup
+        } else if frame < self.startup + self.active {
+            MovePhase::Active((frame - self.startup) as usize)
+        } else {
+            MovePhase::Recovery
+        }
+    }
+}
+
+/// Every move of a fighter, keyed by name, and which one each `AttackHeight` performs.
+///
+/// Loaded from the `*.moves.ron` file a character names.
+#[derive(Asset, TypePath, Clone, Debug, Serialize, Deserialize)]
+pub struct MoveList {
+    pub moves: BTreeMap<String, MoveDef>,
+    pub normal: String,
+    pub low: String,
+}
+
+impl MoveList {
+    pub fn get(&self, name: &str) -> Option<&MoveDef> {
+        self.moves.get(name)
+    }
+
+    /// The special move whose motion the player completed, preferring the most specific
+    /// motion when several did.
+    pub fn for_motion(&self, history: &InputHistory) -> Option<(&str, MotionCommand)> {
+        self.moves
+            .iter()
+            .filter_map(|(name, move_def)| {
+                move_def
+                    .motion
+                    .filter(|motion| history.completed(*motion))
+                    .map(|motion| (name.as_str(), motion))
+            })
+            .min_by_key(|(_, motion)| *motion)
+    }
+
+    /// The charge move with the longest charge that `held` ticks are enough for.
+    pub fn for_charge(&self, held: u32) -> Option<&str> {
+        self.moves
+            .iter()
+            .filter_map(|(name, move_def)| {
+                move_def
+                    .charge
+                    .filter(|charge| held >= *charge)
+                    .map(|charge| (name.as_str(), charge))
+            })
+            .max_by_key(|(_, charge)| *charge)
+            .map(|(name, _)| name)
+    }
+
+    pub fn for_height(&self, height: AttackHeight) -> &str {
+        match height {
+            AttackHeight::Normal => &self.normal,
+            AttackHeight::Low => &self.low,
+        }
+    }
+}
+
+/// The moves a player can perform, from their character. Until the character has loaded,
+/// the built-in defaults are used.
+#[derive(Component, Clone, Debug, Default)]
+pub struct Moveset(pub MoveList);
+
+impl Default for MoveList {
+    fn default() -> Self {
+        let punch = MoveDef {
+            startup: 4,
+            active: 3,
+            recovery: 10,
+            hitboxes: vec![
+                vec![Hitbox {
+                    offset: Vec2::new(20.0, 4.0),
+                    size: Vec2::new(11.0, 11.0),
+                }];
+                3
+            ],
+            damage: 10.0,
+            chip_damage: 1.0,
+            guard: GuardType::Mid,
+            hitstun: 20,
+            blockstun: 12,
+            knockback: Vec2::new(80.0, 0.0),
+            cancels: vec![String::from("sweep")],
+            hitstop: None,
+            shake: None,
+            motion: None,
+            charge: None,
+        };
+        let sweep = MoveDef {
+            startup: 6,
+            active: 4,
+            recovery: 16,
+            hitboxes: vec![
+                vec![Hitbox {
+                    offset: Vec2::new(22.0, -14.0),
+                    size: Vec2::new(20.0, 8.0),
+                }];
+                4
+            ],
+            damage: 12.0,
+            chip_damage: 2.0,
+            guard: GuardType::Low,
+            hitstun: 24,
+            blockstun: 14,
+            knockback: Vec2::new(40.0, 120.0),
+            cancels: Vec::new(),
+            hitstop: Some(10),
+            shake: Some(0.4),
+            motion: None,
+            charge: None,
+        };
+        Self {
+            moves: BTreeMap::from([
+                (String::from("punch"), punch),
+                (String::from("sweep"), sweep),
+            ]),
+            normal: String::from("punch"),
+            low: String::from("sweep"),
+        }
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum MoveListLoaderError {
+    #[error("could not read move list: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse move list: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+    #[error("move list refers to unknown move {0:?}")]
+    UnknownMove(String),
+    #[error("move {0:?} has {1} active frames but hitboxes for {2}")]
+    HitboxFrames(String, u32, usize),
+}
+
+#[derive(Default)]
+pub struct MoveListLoader;
+
+impl AssetLoader for MoveListLoader {
+    type Asset = MoveList;
+    type Settings = ();
+    type Error = MoveListLoaderError;
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            let move_list = ron::de::from_bytes::<MoveList>(&bytes)?;
+            let references = move_list
+                .moves
+                .values()
+                .flat_map(|move_def| move_def.cancels.iter())
+                .chain([&move_list.normal, &move_list.low]);
+            for name in references {
+                if !move_list.moves.contains_key(name) {
+                    return Err(MoveListLoaderError::UnknownMove(name.clone()));
+                }
+            }
+            for (name, move_def) in move_list.moves.iter() {
+                if move_def.hitboxes.len() != move_def.active as usize {
+                    return Err(MoveListLoaderError::HitboxFrames(
+                        name.clone(),
+                        move_def.active,
+                        move_def.hitboxes.len(),
+                    ));
+                }
+            }
+            Ok(move_list)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["moves.ron"]
+    }
+}
+
+pub struct MovesPlugin;
+
+impl Plugin for MovesPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<MoveList>()
+            .init_asset_loader::<MoveListLoader>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn special(motion: MotionCommand) -> MoveDef {
+        MoveDef {
+            motion: Some(motion),
+            ..MoveList::default().moves["punch"].clone()
+        }
+    }
+
+    fn charge(ticks: u32) -> MoveDef {
+        MoveDef {
+            charge: Some(ticks),
+            ..MoveList::default().moves["punch"].clone()
+        }
+    }
+
+    fn completed(commands: &[MotionCommand]) -> InputHistory {
+        InputHistory {
+            directions: Default::default(),
+            recent: commands.iter().map(|command| (*command, 0)).collect(),
+        }
+    }
+
+    #[test]
+    fn for_motion_prefers_the_most_specific_motion() {
+        let mut move_list = MoveList::default();
+        move_list.moves.extend([
+            (
+                String::from("fireball"),
+                special(MotionCommand::QuarterCircleForward),
+            ),
+            (
+                String::from("super"),
+                special(MotionCommand::HalfCircleForward),
+            ),
+        ]);
+        assert_eq!(
+            move_list.for_motion(&completed(&[
+                MotionCommand::QuarterCircleForward,
+                MotionCommand::HalfCircleForward
+            ])),
+            Some(("super", MotionCommand::HalfCircleForward))
+        );
+        assert_eq!(
+            move_list.for_motion(&completed(&[MotionCommand::QuarterCircleForward])),
+            Some(("fireball", MotionCommand::QuarterCircleForward))
+        );
+        assert_eq!(
+            move_list.for_motion(&completed(&[MotionCommand::DragonPunch])),
+            None
+        );
+    }
+
+    #[test]
+    fn for_charge_picks_the_longest_charge_that_is_met() {
+        let mut move_list = MoveList::default();
+        move_list.moves.extend([
+            (String::from("short"), charge(20)),
+            (String::from("long"), charge(60)),
+        ]);
+        assert_eq!(move_list.for_charge(19), None);
+        assert_eq!(move_list.for_charge(20), Some("short"));
+        assert_eq!(move_list.for_charge(59), Some("short"));
+        assert_eq!(move_list.for_charge(90), Some("long"));
+    }
+}
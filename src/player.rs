@@ -1,6 +1,14 @@
-use crate::movement::{PlayerInput, PlayerInputEvent, Velocity};
+use crate::attack::{attack_hit, PlayerBlockEvent, PlayerHitEvent};
+use crate::character::CharacterStats;
+use crate::hitstop::Hitstop;
+use crate::motion::{read_motions, InputHistory, MotionCommand};
+use crate::movement::{InputState, PlayerInput, Velocity};
+use crate::player_state::{update_player_state, PlayerState};
+use crate::rollback::RollbackAppExt;
+use crate::simulation::{GameSet, TIMESTEP};
 use bevy::prelude::*;
 use bevy_rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
 #[derive(Component, Copy, Clone, Debug)]
 pub enum Direction {
     Left,
@@ -12,105 +20,252 @@ pub enum AttackHeight {
     Normal,
 }
 
-#[derive(Component, Clone, Debug)]
-pub struct AttackCooldown(pub Timer);
+#[derive(Component, Copy, Clone, Debug)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            current: 100.0,
+            max: 100.0,
+        }
+    }
+}
+
+/// Super meter, built by landing, blocking and taking hits.
+#[derive(Component, Copy, Clone, Debug)]
+pub struct Meter {
+    pub current: f32,
+    pub max: f32,
+}
+impl Default for Meter {
+    fn default() -> Self {
+        Self {
+            current: 0.0,
+            max: 100.0,
+        }
+    }
+}
+impl Meter {
+    pub fn gain(&mut self, amount: f32) {
+        self.current = (self.current + amount).min(self.max);
+    }
+}
+
+/// Meter gained per point of damage dealt, taken, or chipped off through a block.
+const METER_PER_DAMAGE_DEALT: f32 = 1.0;
+const METER_PER_DAMAGE_TAKEN: f32 = 0.5;
+const METER_PER_CHIP: f32 = 2.0;
 
 #[derive(Component, Clone)]
 pub struct Player;
 
+/// Horizontal speed gained or lost per tick on the ground; scaled by air control in the air.
+const STEERING_ACCELERATION: f32 = 10.0;
+
+pub const MAX_PLAYERS: usize = 2;
+/// Half extents of a standing player's collider.
+pub const PLAYER_HALF_SIZE: Vec2 = Vec2::new(14.0, 20.0);
+
+/// Zero-based player slot; routes collected input to the player it belongs to.
+#[derive(
+    Component,
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+pub struct PlayerId(pub usize);
+
 pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
         app //.add_systems(Startup, spawn_player)
-            .add_systems(PreUpdate, input_player)
-            .add_systems(Update, (player_jump, collision_vel_reset, gravity))
-            .add_systems(PostUpdate, apply_velocity);
+            .rollback_component::<Direction>()
+            .rollback_component::<AttackHeight>()
+            .rollback_component::<Health>()
+            .rollback_component::<Meter>()
+            .rollback_component::<KinematicCharacterController>()
+            .rollback_component::<KinematicCharacterControllerOutput>()
+            .add_systems(
+                FixedUpdate,
+                face_opponent.before(read_motions).in_set(GameSet::Player),
+            )
+            .add_systems(
+                FixedUpdate,
+                (input_player, collision_vel_reset, gravity)
+                    .chain()
+                    .after(update_player_state)
+                    .in_set(GameSet::Player),
+            )
+            .add_systems(
+                FixedUpdate,
+                gain_meter.after(attack_hit).in_set(GameSet::Combat),
+            )
+            .add_systems(FixedUpdate, apply_velocity.in_set(GameSet::Movement));
     }
 }
 
-fn input_player(
-    mut query: Query<(&mut AttackHeight, &mut Velocity, &mut Direction), With<Player>>,
-    mut ev_input: EventReader<PlayerInputEvent>,
+/// Turns players toward the nearest opponent whenever they are free to, so back and
+/// forward mean away from and toward them. Runs before motions are read, which are relative
+/// to facing.
+fn face_opponent(
+    mut query: Query<(Entity, &Transform, &PlayerState, &Hitstop, &mut Direction), With<Player>>,
 ) {
-    for input in ev_input.read() {
-        for (mut attack_height, mut velocity, mut direction) in query.iter_mut() {
-            if input.0.contains(&PlayerInput::Left) {
-                velocity.velocity.x = (-velocity.max_speed).max(velocity.velocity.x - 10.0);
-                *direction = Direction::Left;
-            } else if input.0.contains(&PlayerInput::Right) {
-                velocity.velocity.x = velocity.max_speed.min(velocity.velocity.x + 10.0);
-                *direction = Direction::Right;
-            } else if velocity.velocity.x > 0.0 {
-                velocity.velocity.x = (velocity.velocity.x - 10.0).max(0.0);
-            } else if velocity.velocity.x < 0.0 {
-                velocity.velocity.x = (velocity.velocity.x + 10.0).min(0.0);
-            }
+    let positions: Vec<(Entity, f32)> = query
+        .iter()
+        .map(|(entity, transform, ..)| (entity, transform.translation.x))
+        .collect();
+    for (entity, transform, state, hitstop, mut direction) in query.iter_mut() {
+        if !state.can_turn() || hitstop.frozen() {
+            continue;
+        }
+        let x = transform.translation.x;
+        let Some(opponent_x) = positions
+            .iter()
+            .filter(|(other, _)| *other != entity)
+            .map(|(_, other_x)| *other_x)
+            .min_by(|a, b| (a - x).abs().total_cmp(&(b - x).abs()))
+        else {
+            continue;
+        };
+        if opponent_x < x {
+            *direction = Direction::Left;
+        } else if opponent_x > x {
+            *direction = Direction::Right;
+        }
+    }
+}
 
-            // controller.translation = Some(velocity.velocity * time.delta_seconds());
-            if input.0.contains(&PlayerInput::Down) {
-                *attack_height = AttackHeight::Low;
-            } else {
-                *attack_height = AttackHeight::Normal;
-            }
+/// What decides how a player walks, runs and drifts, and the velocity it changes.
+type Steering<'a> = (
+    &'a InputState,
+    &'a PlayerState,
+    &'a Hitstop,
+    &'a CharacterStats,
+    &'a InputHistory,
+    &'a mut Velocity,
+);
+
+fn input_player(mut query: Query<Steering, With<Player>>) {
+    for (input, state, hitstop, stats, history, mut velocity) in query.iter_mut() {
+        if hitstop.frozen() {
+            continue;
+        }
+        let steering = state.can_steer();
+        // a double tap runs in the tapped direction for as long as it is held
+        let dashed = history.completed(MotionCommand::DashForward)
+            || history.completed(MotionCommand::DashBack);
+        let holding = input.pressed(PlayerInput::Left) || input.pressed(PlayerInput::Right);
+        if steering && dashed && !state.is_airborne() {
+            velocity.max_speed = stats.run_speed;
+        } else if !steering || !holding {
+            velocity.max_speed = stats.walk_speed;
         }
+        let acceleration = if state.is_airborne() {
+            STEERING_ACCELERATION * stats.air_control
+        } else {
+            STEERING_ACCELERATION
+        };
+        if steering && input.pressed(PlayerInput::Left) {
+            velocity.velocity.x = (-velocity.max_speed).max(velocity.velocity.x - acceleration);
+        } else if steering && input.pressed(PlayerInput::Right) {
+            velocity.velocity.x = velocity.max_speed.min(velocity.velocity.x + acceleration);
+        } else if *state == PlayerState::Juggle {
+            // launched players keep their momentum until they land
+        } else if velocity.velocity.x > 0.0 {
+            velocity.velocity.x = (velocity.velocity.x - acceleration).max(0.0);
+        } else if velocity.velocity.x < 0.0 {
+            velocity.velocity.x = (velocity.velocity.x + acceleration).min(0.0);
+        }
+
+        // controller.translation = Some(velocity.velocity * time.delta_seconds());
     }
 }
 
-fn player_jump(
-    mut query: Query<(&mut Velocity, &KinematicCharacterControllerOutput), With<Player>>,
-    mut ev_input: EventReader<PlayerInputEvent>,
+fn gain_meter(
+    mut query: Query<&mut Meter, With<Player>>,
+    mut ev_hit: EventReader<PlayerHitEvent>,
+    mut ev_block: EventReader<PlayerBlockEvent>,
 ) {
-    for input in ev_input.read() {
-        for (mut velocity, controller) in query.iter_mut() {
-            if input.0.contains(&PlayerInput::Up) && controller.grounded {
-                velocity.velocity.y = 200.0;
-            }
+    for hit in ev_hit.read() {
+        if let Ok(mut meter) = query.get_mut(hit.attacker) {
+            meter.gain(hit.damage * METER_PER_DAMAGE_DEALT);
+        }
+        if let Ok(mut meter) = query.get_mut(hit.victim) {
+            meter.gain(hit.damage * METER_PER_DAMAGE_TAKEN);
+        }
+    }
+    for block in ev_block.read() {
+        if let Ok(mut meter) = query.get_mut(block.attacker) {
+            meter.gain(block.chip_damage * METER_PER_CHIP);
+        }
+        if let Ok(mut meter) = query.get_mut(block.defender) {
+            meter.gain(block.chip_damage * METER_PER_CHIP);
         }
     }
 }
 
 pub fn gravity(
-    mut query: Query<(&mut Velocity, &KinematicCharacterControllerOutput), With<Player>>,
-    time: Res<Time>,
+    mut query: Query<
+        (
+            &mut Velocity,
+            &KinematicCharacterControllerOutput,
+            &Hitstop,
+            &CharacterStats,
+        ),
+        With<Player>,
+    >,
 ) {
-    let delta_y = -400.0 * time.delta_seconds();
-    for (mut velocity, character_controller) in query.iter_mut() {
-        if !character_controller.grounded {
-            velocity.velocity.y += delta_y;
+    for (mut velocity, character_controller, hitstop, stats) in query.iter_mut() {
+        if !character_controller.grounded && !hitstop.frozen() {
+            velocity.velocity.y -= stats.gravity * TIMESTEP;
         }
     }
 }
 
 fn collision_vel_reset(
-    mut query: Query<(&mut Velocity, &KinematicCharacterControllerOutput), With<Player>>,
+    mut query: Query<(&mut Velocity, &KinematicCharacterControllerOutput, &Hitstop), With<Player>>,
 ) {
-    for (mut velocity, character_controller) in query.iter_mut() {
+    for (mut velocity, character_controller, hitstop) in query.iter_mut() {
+        // the contacts are from before the freeze and would eat fresh knockback
+        if hitstop.frozen() {
+            continue;
+        }
         for contact in character_controller.collisions.iter() {
-            match contact.toi.details {
-                Some(c) => {
-                    if c.normal1.y < -0.5 {
-                        velocity.velocity.y = 0.0;
-                    }
-                    if c.normal1.y > 0.5 && velocity.velocity.y < 0.0 {
-                        velocity.velocity.y = 0.0;
-                    }
-                    if c.normal1.x.abs() > 0.5 {
-                        velocity.velocity.x = 0.0;
-                    }
+            if let Some(c) = contact.toi.details {
+                if c.normal1.y < -0.5 {
+                    velocity.velocity.y = 0.0;
+                }
+                if c.normal1.y > 0.5 && velocity.velocity.y < 0.0 {
+                    velocity.velocity.y = 0.0;
+                }
+                if c.normal1.x.abs() > 0.5 {
+                    velocity.velocity.x = 0.0;
                 }
-
-                _ => {}
             }
         }
     }
 }
 
-fn apply_velocity(
-    time: Res<Time>,
-    mut query: Query<(&Velocity, &mut KinematicCharacterController), With<Player>>,
+pub fn apply_velocity(
+    mut query: Query<(&Velocity, &Hitstop, &mut KinematicCharacterController), With<Player>>,
 ) {
-    for (velocity, mut controller) in query.iter_mut() {
-        controller.translation = Some(velocity.velocity * time.delta_seconds());
+    for (velocity, hitstop, mut controller) in query.iter_mut() {
+        controller.translation = if hitstop.frozen() {
+            Some(Vec2::ZERO)
+        } else {
+            Some(velocity.velocity * TIMESTEP)
+        };
     }
 }
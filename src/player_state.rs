@@ -0,0 +1,414 @@
+use crate::attack::{attack_hit, PlayerBlockEvent, PlayerHitEvent};
+use crate::character::CharacterStats;
+use crate::hitstop::Hitstop;
+use crate::input_buffer::InputBuffer;
+use crate::motion::InputHistory;
+use crate::movement::{InputState, PlayerInput, Velocity};
+use crate::moves::{GuardType, MovePhase, Moveset};
+use crate::player::{AttackHeight, Direction, Health, Player, PlayerId};
+use crate::rollback::{Resimulating, RollbackAppExt};
+use crate::simulation::{FrameTimer, GameSet};
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+const KNOCKDOWN_FRAMES: u32 = 60;
+/// Smallest upward speed of a player hit in the air, so juggles always pop them up.
+const JUGGLE_POP_SPEED: f32 = 120.0;
+
+#[derive(Component, Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PlayerState {
+    #[default]
+    Idle,
+    Walk,
+    Jump,
+    Fall,
+    Crouch,
+    Attack,
+    Hitstun,
+    /// Launched into the air by a hit; lasts until landing, which knocks the player down.
+    Juggle,
+    Blockstun,
+    Knockdown,
+}
+
+impl PlayerState {
+    /// Horizontal input accelerates the player and turns them around.
+    pub fn can_steer(self) -> bool {
+        matches!(self, Self::Idle | Self::Walk | Self::Jump | Self::Fall)
+    }
+
+    pub fn can_attack(self) -> bool {
+        matches!(
+            self,
+            Self::Idle | Self::Walk | Self::Crouch | Self::Jump | Self::Fall
+        )
+    }
+
+    /// On the ground with nothing going on, so the player may turn around.
+    pub fn can_turn(self) -> bool {
+        matches!(self, Self::Idle | Self::Walk | Self::Crouch)
+    }
+
+    pub fn is_airborne(self) -> bool {
+        matches!(self, Self::Jump | Self::Fall | Self::Juggle)
+    }
+
+    /// Blocking needs both feet on the ground and nothing else going on.
+    pub fn can_block(self) -> bool {
+        matches!(
+            self,
+            Self::Idle | Self::Walk | Self::Crouch | Self::Blockstun
+        )
+    }
+}
+
+/// Optional platform-fighter rule: knockback grows with the damage a player has taken.
+/// Enabled with `--scaled-knockback`.
+#[derive(Resource, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KnockbackScaling {
+    pub enabled: bool,
+    /// Extra knockback, as a fraction, per point of damage taken.
+    pub per_damage: f32,
+}
+
+impl Default for KnockbackScaling {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            per_damage: 0.01,
+        }
+    }
+}
+
+impl KnockbackScaling {
+    pub fn multiplier(&self, health: &Health) -> f32 {
+        if self.enabled {
+            1.0 + (health.max - health.current) * self.per_damage
+        } else {
+            1.0
+        }
+    }
+}
+
+/// The directions a player held this tick, checked when an attack reaches them.
+#[derive(Component, Copy, Clone, Debug, Default)]
+pub struct Guard {
+    pub holding: Option<Direction>,
+    pub crouching: bool,
+}
+
+impl Guard {
+    /// Holding away from the attacker blocks; standing guards mids and overheads, crouching
+    /// guards mids and lows. `attacker_offset` is the attacker's x minus the defender's.
+    pub fn blocks(&self, guard_type: GuardType, attacker_offset: f32) -> bool {
+        let holding_away = match self.holding {
+            Some(Direction::Left) => attacker_offset > 0.0,
+            Some(Direction::Right) => attacker_offset < 0.0,
+            None => false,
+        };
+        holding_away
+            && match guard_type {
+                GuardType::Mid => true,
+                GuardType::Low => self.crouching,
+                GuardType::Overhead => !self.crouching,
+                GuardType::Unblockable => false,
+            }
+    }
+}
+
+/// The move being performed while in `PlayerState::Attack`. Its current frame is the
+/// elapsed time of the `StateTimer`.
+#[derive(Component, Clone, Debug, Default)]
+pub struct CurrentMove {
+    pub name: String,
+    /// The move hit someone, which unlocks its cancels.
+    pub connected: bool,
+}
+
+/// Ticks spent in a timed state (attack, hitstun, blockstun, knockdown); finished means the state
+/// may be left.
+#[derive(Component, Copy, Clone, Debug)]
+pub struct StateTimer(pub FrameTimer);
+
+impl Default for StateTimer {
+    fn default() -> Self {
+        Self(FrameTimer::new(0))
+    }
+}
+
+#[derive(Event, Copy, Clone, Debug)]
+pub struct PlayerStateChangedEvent {
+    pub entity: Entity,
+    pub player: PlayerId,
+    pub from: PlayerState,
+    pub to: PlayerState,
+}
+
+pub struct PlayerStatePlugin;
+
+impl Plugin for PlayerStatePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<PlayerStateChangedEvent>()
+            .insert_resource(KnockbackScaling {
+                enabled: std::env::args().any(|arg| arg == "--scaled-knockback"),
+                ..Default::default()
+            })
+            .rollback_component::<PlayerState>()
+            .rollback_component::<StateTimer>()
+            .rollback_component::<CurrentMove>()
+            .rollback_component::<Guard>()
+            .add_systems(FixedUpdate, update_player_state.in_set(GameSet::Player))
+            .add_systems(
+                FixedUpdate,
+                (react_to_hits, react_to_blocks, log_state_changes)
+                    .chain()
+                    .after(attack_hit)
+                    .in_set(GameSet::Combat),
+            );
+    }
+}
+
+fn set_state(
+    entity: Entity,
+    player: PlayerId,
+    state: &mut PlayerState,
+    next: PlayerState,
+    ev_state: &mut EventWriter<PlayerStateChangedEvent>,
+) {
+    if *state == next {
+        return;
+    }
+    ev_state.send(PlayerStateChangedEvent {
+        entity,
+        player,
+        from: *state,
+        to: next,
+    });
+    *state = next;
+}
+
+/// Everything a player's next state is picked from, and what picking it changes.
+type StateMachine<'a> = (
+    Entity,
+    &'a PlayerId,
+    &'a mut PlayerState,
+    &'a mut StateTimer,
+    &'a mut CurrentMove,
+    &'a mut AttackHeight,
+    &'a mut Guard,
+    &'a mut Velocity,
+    &'a KinematicCharacterControllerOutput,
+    &'a Health,
+    &'a Hitstop,
+    &'a Moveset,
+    &'a CharacterStats,
+    (&'a InputState, &'a mut InputHistory, &'a mut InputBuffer),
+);
+
+/// Picks every player's state for this tick from their input, buffered presses, the ground
+/// contact and the timer of the current state.
+pub fn update_player_state(
+    mut query: Query<StateMachine, With<Player>>,
+    mut ev_state: EventWriter<PlayerStateChangedEvent>,
+) {
+    for (
+        entity,
+        player_id,
+        mut state,
+        mut state_timer,
+        mut current_move,
+        mut attack_height,
+        mut guard,
+        mut velocity,
+        controller,
+        health,
+        hitstop,
+        moveset,
+        stats,
+        (input_state, mut history, mut buffer),
+    ) in query.iter_mut()
+    {
+        if hitstop.frozen() {
+            continue;
+        }
+        state_timer.0.tick();
+        let input = &input_state.current;
+        let airborne = !controller.grounded || velocity.velocity.y > 0.0;
+        *guard = Guard {
+            holding: if input.contains(&PlayerInput::Left) {
+                Some(Direction::Left)
+            } else if input.contains(&PlayerInput::Right) {
+                Some(Direction::Right)
+            } else {
+                None
+            },
+            crouching: input.contains(&PlayerInput::Down),
+        };
+
+        let height = if input.contains(&PlayerInput::Down) {
+            AttackHeight::Low
+        } else {
+            AttackHeight::Normal
+        };
+        let move_list = &moveset.0;
+        // a completed motion turns a press into that special move, and letting go of attack
+        // after holding it long enough performs a charge move
+        let special = move_list.for_motion(&history);
+        let charged = move_list
+            .for_charge(buffer.charged)
+            .filter(|_| buffer.release > 0);
+        let (requested, triggered) = match (special, charged) {
+            (Some((name, _)), _) if buffer.attack > 0 => (name, true),
+            (_, Some(name)) => (name, true),
+            _ => (move_list.for_height(height), buffer.attack > 0),
+        };
+        // a move that vanished in a hot reload simply ends
+        let performing = move_list
+            .get(&current_move.name)
+            .filter(|_| *state == PlayerState::Attack);
+        let cancellable = current_move.connected
+            && performing.is_some_and(|move_def| {
+                move_def.phase(state_timer.0.elapsed) != MovePhase::Startup
+                    && move_def.cancels.iter().any(|name| name == requested)
+            });
+        let next_move = move_list
+            .get(requested)
+            .filter(|_| triggered)
+            .filter(|_| state.can_attack() || cancellable);
+
+        let next = match *state {
+            PlayerState::Knockdown if health.current <= 0.0 => PlayerState::Knockdown,
+            PlayerState::Hitstun | PlayerState::Blockstun | PlayerState::Knockdown
+                if !state_timer.0.finished() =>
+            {
+                *state
+            }
+            PlayerState::Juggle if airborne => PlayerState::Juggle,
+            PlayerState::Juggle => {
+                state_timer.0 = FrameTimer::new(KNOCKDOWN_FRAMES);
+                PlayerState::Knockdown
+            }
+            _ if next_move.is_some() => {
+                if let Some((_, motion)) = special {
+                    history.consume(motion);
+                }
+                buffer.attack = 0;
+                buffer.release = 0;
+                let frames = next_move.map_or(0, |move_def| move_def.total_frames());
+                state_timer.0 = FrameTimer::new(frames);
+                *current_move = CurrentMove {
+                    name: requested.to_string(),
+                    connected: false,
+                };
+                *attack_height = height;
+                PlayerState::Attack
+            }
+            PlayerState::Attack if performing.is_some() && !state_timer.0.finished() => {
+                PlayerState::Attack
+            }
+            // a buffered jump fires on landing, or shortly after walking off a ledge
+            _ if buffer.jump > 0 && (!airborne || buffer.coyote > 0) => {
+                buffer.jump = 0;
+                buffer.coyote = 0;
+                velocity.velocity.y = stats.jump_speed;
+                PlayerState::Jump
+            }
+            _ if airborne => {
+                if velocity.velocity.y > 0.0 {
+                    PlayerState::Jump
+                } else {
+                    PlayerState::Fall
+                }
+            }
+            _ if input.contains(&PlayerInput::Down) => PlayerState::Crouch,
+            _ if input.contains(&PlayerInput::Left) || input.contains(&PlayerInput::Right) => {
+                PlayerState::Walk
+            }
+            _ => PlayerState::Idle,
+        };
+        set_state(entity, *player_id, &mut state, next, &mut ev_state);
+    }
+}
+
+/// What a hit changes on its victim, and what decides how.
+type HitVictim<'a> = (
+    &'a PlayerId,
+    &'a mut PlayerState,
+    &'a mut StateTimer,
+    &'a mut Velocity,
+    &'a Health,
+    &'a CharacterStats,
+);
+
+/// Hits push the victim along the attack's knockback, less for heavier fighters. Grounded
+/// victims go into hitstun unless the knockback launches them; victims already in the air
+/// are juggled.
+fn react_to_hits(
+    mut query: Query<HitVictim, With<Player>>,
+    scaling: Res<KnockbackScaling>,
+    mut ev_hit: EventReader<PlayerHitEvent>,
+    mut ev_state: EventWriter<PlayerStateChangedEvent>,
+) {
+    for hit in ev_hit.read() {
+        let Ok((player_id, mut state, mut state_timer, mut velocity, health, stats)) =
+            query.get_mut(hit.victim)
+        else {
+            continue;
+        };
+        velocity.velocity = hit.knockback * scaling.multiplier(health) / stats.weight;
+        let next = if health.current <= 0.0 {
+            state_timer.0 = FrameTimer::new(KNOCKDOWN_FRAMES);
+            PlayerState::Knockdown
+        } else if state.is_airborne() {
+            velocity.velocity.y = velocity.velocity.y.max(JUGGLE_POP_SPEED);
+            PlayerState::Juggle
+        } else if velocity.velocity.y > 0.0 {
+            PlayerState::Juggle
+        } else {
+            state_timer.0 = FrameTimer::new(hit.hitstun);
+            PlayerState::Hitstun
+        };
+        set_state(hit.victim, *player_id, &mut state, next, &mut ev_state);
+    }
+}
+
+/// Blocked attacks hold the defender in blockstun; chip damage that finishes them knocks
+/// them down instead.
+pub fn react_to_blocks(
+    mut query: Query<(&PlayerId, &mut PlayerState, &mut StateTimer, &Health), With<Player>>,
+    mut ev_block: EventReader<PlayerBlockEvent>,
+    mut ev_state: EventWriter<PlayerStateChangedEvent>,
+) {
+    for block in ev_block.read() {
+        let Ok((player_id, mut state, mut state_timer, health)) = query.get_mut(block.defender)
+        else {
+            continue;
+        };
+        let next = if health.current <= 0.0 {
+            state_timer.0 = FrameTimer::new(KNOCKDOWN_FRAMES);
+            PlayerState::Knockdown
+        } else {
+            state_timer.0 = FrameTimer::new(block.blockstun);
+            PlayerState::Blockstun
+        };
+        set_state(block.defender, *player_id, &mut state, next, &mut ev_state);
+    }
+}
+
+/// Logs every state change at debug level, once per tick rather than again on rollback.
+fn log_state_changes(
+    resimulating: Res<Resimulating>,
+    mut ev_state: EventReader<PlayerStateChangedEvent>,
+) {
+    if resimulating.0 {
+        ev_state.clear();
+        return;
+    }
+    for change in ev_state.read() {
+        debug!(
+            "{:?} ({:?}): {:?} -> {:?}",
+            change.player, change.entity, change.from, change.to
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use crate::character::{Character, CharacterSettings};
+use crate::movement::{InputSet, InputState, LastInput, PendingInput, PlayerInput, Velocity};
+use crate::player::{Player, PlayerId};
+use crate::simulation::{GameSet, SimulationConfig, SimulationSettings};
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy_ecs_ldtk::prelude::*;
+use bevy_rapier2d::prelude::PhysicsSet;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bumped whenever the layout of `ReplayFile` or the simulation rules change.
+pub const REPLAY_VERSION: u32 = 11;
+
+const REPLAY_DIR: &str = "replays";
+const FAST_FORWARD_SPEED: f32 = 4.0;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ReplayFrame {
+    pub inputs: Vec<(PlayerId, Vec<PlayerInput>)>,
+    /// `state_checksum` of every player after this frame was simulated.
+    pub checksum: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplayFile {
+    pub version: u32,
+    pub level: String,
+    /// Each player's character as it was when recording started, so a replay is played
+    /// back with the same fighters even after their files change.
+    pub characters: Vec<(PlayerId, CharacterSettings)>,
+    pub settings: SimulationConfig,
+    pub frames: Vec<ReplayFrame>,
+}
+
+pub struct ReplayRecorder {
+    path: PathBuf,
+    file: Option<ReplayFile>,
+}
+
+pub struct ReplayPlayer {
+    file: ReplayFile,
+    started: bool,
+    cursor: usize,
+    desynced: bool,
+}
+
+#[derive(Resource, Default)]
+pub enum Replay {
+    #[default]
+    Idle,
+    Recording(ReplayRecorder),
+    Playing(ReplayPlayer),
+}
+
+#[derive(Event, Copy, Clone, Debug)]
+pub struct ReplayDesyncEvent {
+    pub frame: usize,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Replay>()
+            .add_event::<ReplayDesyncEvent>()
+            .add_systems(Startup, load_replay_from_args)
+            .add_systems(
+                FixedUpdate,
+                (
+                    start_replay,
+                    feed_replay_input
+                        .after(InputSet::Collect)
+                        .before(InputSet::Write),
+                    record_replay_input.after(InputSet::Write),
+                )
+                    .chain()
+                    .in_set(GameSet::Input),
+            )
+            .add_systems(
+                FixedUpdate,
+                check_replay_frame
+                    .after(PhysicsSet::Writeback)
+                    .in_set(GameSet::Physics),
+            )
+            .add_systems(Update, (toggle_recording, playback_controls, report_desync))
+            .add_systems(Update, step_replay.after(playback_controls))
+            .add_systems(Last, save_recording_on_exit);
+    }
+}
+
+/// FNV-1a over the position and velocity of every player, ordered by slot.
+pub fn state_checksum<'a>(
+    players: impl Iterator<Item = (&'a PlayerId, &'a Transform, &'a Velocity)>,
+) -> u64 {
+    let mut players: Vec<_> = players.collect();
+    players.sort_by_key(|(player_id, _, _)| **player_id);
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for (player_id, transform, velocity) in players {
+        let values = [
+            player_id.0 as u32,
+            transform.translation.x.to_bits(),
+            transform.translation.y.to_bits(),
+            velocity.velocity.x.to_bits(),
+            velocity.velocity.y.to_bits(),
+        ];
+        for value in values {
+            for byte in value.to_le_bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+    }
+    hash
+}
+
+/// Runs before the match starts, so its rules are in place for the first round.
+fn load_replay_from_args(
+    mut replay: ResMut<Replay>,
+    mut level_selection: ResMut<LevelSelection>,
+    mut settings: SimulationSettings,
+) {
+    let args: Vec<String> = std::env::args().collect();
+    let Some(path) = args
+        .iter()
+        .position(|arg| arg == "--replay")
+        .and_then(|index| args.get(index + 1))
+    else {
+        return;
+    };
+    let file = std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|contents| {
+            ron::de::from_str::<ReplayFile>(&contents).map_err(|err| err.to_string())
+        });
+    match file {
+        Ok(file) if file.version == REPLAY_VERSION => {
+            info!("playing replay {} ({} frames)", path, file.frames.len());
+            *level_selection = LevelSelection::iid(file.level.clone());
+            settings.set(file.settings);
+            *replay = Replay::Playing(ReplayPlayer {
+                file,
+                started: false,
+                cursor: 0,
+                desynced: false,
+            });
+        }
+        Ok(file) => error!(
+            "replay {} has version {}, expected {}",
+            path, file.version, REPLAY_VERSION
+        ),
+        Err(err) => error!("could not load replay {}: {}", path, err),
+    }
+}
+
+/// Recording and playback both begin on the first tick after the players have their
+/// characters, with nothing held and nothing carried over to respawns, so the recorded
+/// inputs line up with the same starting state.
+fn start_replay(
+    mut commands: Commands,
+    mut replay: ResMut<Replay>,
+    spawned: Query<(), Added<Character>>,
+    level_query: Query<&LevelIid>,
+    settings: SimulationSettings,
+    mut last_input: ResMut<LastInput>,
+    mut player_query: Query<(Entity, &PlayerId, &mut Character, &mut InputState), With<Player>>,
+) {
+    if spawned.is_empty() {
+        return;
+    }
+    match replay.as_mut() {
+        Replay::Recording(recorder) if recorder.file.is_none() => {
+            last_input.0.clear();
+            let mut characters: Vec<(PlayerId, CharacterSettings)> = player_query
+                .iter_mut()
+                .map(|(_, player_id, character, mut input)| {
+                    *input = InputState::default();
+                    (*player_id, character.settings.clone())
+                })
+                .collect();
+            characters.sort_by_key(|(player_id, _)| *player_id);
+            recorder.file = Some(ReplayFile {
+                version: REPLAY_VERSION,
+                level: level_query
+                    .iter()
+                    .next()
+                    .map(|level_iid| level_iid.to_string())
+                    .unwrap_or_default(),
+                characters,
+                settings: settings.get(),
+                frames: Vec::new(),
+            });
+            info!("recording replay to {}", recorder.path.display());
+        }
+        Replay::Playing(playback) if !playback.started => {
+            last_input.0.clear();
+            for (entity, player_id, mut character, mut input) in player_query.iter_mut() {
+                *input = InputState::default();
+                let Some((_, settings)) = playback
+                    .file
+                    .characters
+                    .iter()
+                    .find(|(player, _)| player == player_id)
+                else {
+                    continue;
+                };
+                settings.apply(&mut commands.entity(entity));
+                character.settings = settings.clone();
+            }
+            playback.started = true;
+        }
+        _ => {}
+    }
+}
+
+fn feed_replay_input(replay: Res<Replay>, mut pending: ResMut<PendingInput>) {
+    let Replay::Playing(playback) = replay.as_ref() else {
+        return;
+    };
+    // devices are ignored while a replay drives the players
+    pending.0.clear();
+    if !playback.started {
+        return;
+    }
+    let Some(frame) = playback.file.frames.get(playback.cursor) else {
+        return;
+    };
+    for (player_id, inputs) in frame.inputs.iter() {
+        pending
+            .0
+            .insert(*player_id, inputs.iter().copied().collect());
+    }
+}
+
+fn record_replay_input(
+    mut replay: ResMut<Replay>,
+    player_query: Query<(&PlayerId, &InputState), With<Player>>,
+) {
+    let Replay::Recording(ReplayRecorder {
+        file: Some(file), ..
+    }) = replay.as_mut()
+    else {
+        return;
+    };
+    let mut frame = ReplayFrame::default();
+    for (player_id, input) in player_query.iter() {
+        let mut actions: Vec<PlayerInput> = input.current.iter().copied().collect();
+        actions.sort();
+        frame.inputs.push((*player_id, actions));
+    }
+    frame.inputs.sort_by_key(|(player_id, _)| *player_id);
+    file.frames.push(frame);
+}
+
+fn check_replay_frame(
+    mut replay: ResMut<Replay>,
+    mut time: ResMut<Time<Virtual>>,
+    player_query: Query<(&PlayerId, &Transform, &Velocity), With<Player>>,
+    mut ev_desync: EventWriter<ReplayDesyncEvent>,
+) {
+    let checksum = state_checksum(player_query.iter());
+    match replay.as_mut() {
+        Replay::Recording(ReplayRecorder {
+            file: Some(file), ..
+        }) => {
+            if let Some(frame) = file.frames.last_mut() {
+                frame.checksum = checksum;
+            }
+        }
+        Replay::Playing(playback) if playback.started => {
+            let Some(frame) = playback.file.frames.get(playback.cursor) else {
+                return;
+            };
+            if frame.checksum != checksum && !playback.desynced {
+                playback.desynced = true;
+                ev_desync.send(ReplayDesyncEvent {
+                    frame: playback.cursor,
+                    expected: frame.checksum,
+                    actual: checksum,
+                });
+            }
+            playback.cursor += 1;
+            if playback.cursor == playback.file.frames.len() {
+                info!("replay finished after {} frames", playback.cursor);
+                time.set_relative_speed(1.0);
+                *replay = Replay::Idle;
+            }
+        }
+        _ => {}
+    }
+}
+
+fn report_desync(mut ev_desync: EventReader<ReplayDesyncEvent>) {
+    for desync in ev_desync.read() {
+        error!(
+            "replay desync at frame {}: expected checksum {:016x}, got {:016x}",
+            desync.frame, desync.expected, desync.actual
+        );
+    }
+}
+
+fn save_replay(recorder: &ReplayRecorder) {
+    let Some(file) = &recorder.file else {
+        return;
+    };
+    let result = ron::ser::to_string_pretty(file, ron::ser::PrettyConfig::default())
+        .map_err(|err| err.to_string())
+        .and_then(|contents| {
+            if let Some(dir) = recorder.path.parent() {
+                std::fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+            }
+            std::fs::write(&recorder.path, contents).map_err(|err| err.to_string())
+        });
+    match result {
+        Ok(()) => info!(
+            "saved replay {} ({} frames)",
+            recorder.path.display(),
+            file.frames.len()
+        ),
+        Err(err) => error!("could not save replay {}: {}", recorder.path.display(), err),
+    }
+}
+
+/// F5 restarts the level and records from the fresh spawn; pressing it again saves the file.
+fn toggle_recording(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut replay: ResMut<Replay>,
+    level_query: Query<Entity, With<LevelIid>>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::F5) {
+        return;
+    }
+    match replay.as_ref() {
+        Replay::Idle => {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default();
+            *replay = Replay::Recording(ReplayRecorder {
+                path: PathBuf::from(REPLAY_DIR).join(format!("replay-{}.ron", timestamp)),
+                file: None,
+            });
+            for level_entity in &level_query {
+                commands.entity(level_entity).insert(Respawn);
+            }
+        }
+        Replay::Recording(recorder) => {
+            save_replay(recorder);
+            *replay = Replay::Idle;
+        }
+        Replay::Playing(_) => {}
+    }
+}
+
+/// P pauses, period steps a single frame while paused, holding F fast-forwards.
+fn playback_controls(
+    keyboard_input: Res<Input<KeyCode>>,
+    replay: Res<Replay>,
+    mut time: ResMut<Time<Virtual>>,
+) {
+    if !matches!(replay.as_ref(), Replay::Playing(_)) {
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::P) {
+        if time.is_paused() {
+            time.unpause();
+        } else {
+            time.pause();
+        }
+    }
+    if keyboard_input.pressed(KeyCode::F) {
+        time.set_relative_speed(FAST_FORWARD_SPEED);
+    } else {
+        time.set_relative_speed(1.0);
+    }
+}
+
+fn step_replay(world: &mut World) {
+    let stepping = matches!(world.resource::<Replay>(), Replay::Playing(_))
+        && world.resource::<Time<Virtual>>().is_paused()
+        && world
+            .resource::<Input<KeyCode>>()
+            .just_pressed(KeyCode::Period);
+    if stepping {
+        world.run_schedule(FixedUpdate);
+    }
+}
+
+fn save_recording_on_exit(replay: Res<Replay>, mut ev_exit: EventReader<AppExit>) {
+    if ev_exit.read().next().is_none() {
+        return;
+    }
+    if let Replay::Recording(recorder) = replay.as_ref() {
+        save_replay(recorder);
+    }
+}
@@ -0,0 +1,1056 @@
+use crate::character::{Character, CharacterRoster, CharacterSelection};
+use crate::movement::{InputSet, PendingInput, PlayerInput};
+use crate::player::{Player, PlayerId};
+use crate::simulation::{
+    advance_frame, GameSet, SimulationConfig, SimulationFrame, SimulationSettings,
+};
+use crate::transport::{LinkConditions, LoopbackTransport, Transport, UdpTransport};
+use crate::world::{selected_level_iid, LdtkProjectHandle};
+use bevy::ecs::system::{RunSystemOnce, SystemParam};
+use bevy::prelude::*;
+use bevy_ecs_ldtk::prelude::*;
+use bevy_rapier2d::plugin::systems::sync_removals;
+use bevy_rapier2d::prelude::RapierContext;
+use bevy_rapier2d::rapier::prelude::{
+    BroadPhase, ColliderSet, IslandManager, NarrowPhase, QueryPipeline, RigidBodySet,
+};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// How far the simulation may run ahead of the last confirmed remote input. Also the
+/// deepest rollback that can happen, and how many past inputs each packet repeats.
+pub const MAX_ROLLBACK_FRAMES: u64 = 8;
+
+/// Entities whose registered components are saved and restored on rollback.
+#[derive(Component, Copy, Clone, Debug, Default)]
+pub struct Rollback;
+
+/// Set while `rollback` simulates frames that were already simulated once. Presentation
+/// that reacts to simulation events, like screen shake, skips these frames so it does not
+/// react to the same hit twice.
+#[derive(Resource, Default, Copy, Clone, Debug)]
+pub struct Resimulating(pub bool);
+
+type SavedState = Box<dyn Any + Send + Sync>;
+
+#[derive(Copy, Clone)]
+struct RollbackFns {
+    save: fn(&mut World) -> SavedState,
+    load: fn(&mut World, &SavedState),
+}
+
+/// Every component and resource that makes up the gameplay state.
+#[derive(Resource, Default)]
+pub struct RollbackRegistry {
+    components: Vec<RollbackFns>,
+    resources: Vec<RollbackFns>,
+}
+
+pub trait RollbackAppExt {
+    /// Save and restore `C` on every `Rollback` entity.
+    fn rollback_component<C: Component + Clone>(&mut self) -> &mut Self;
+    /// Save and restore the `R` resource.
+    fn rollback_resource<R: Resource + Clone>(&mut self) -> &mut Self;
+}
+
+impl RollbackAppExt for App {
+    fn rollback_component<C: Component + Clone>(&mut self) -> &mut Self {
+        self.world
+            .get_resource_or_insert_with(RollbackRegistry::default)
+            .components
+            .push(RollbackFns {
+                save: save_component::<C>,
+                load: load_component::<C>,
+            });
+        self
+    }
+
+    fn rollback_resource<R: Resource + Clone>(&mut self) -> &mut Self {
+        self.world
+            .get_resource_or_insert_with(RollbackRegistry::default)
+            .resources
+            .push(RollbackFns {
+                save: save_resource::<R>,
+                load: load_resource::<R>,
+            });
+        self
+    }
+}
+
+fn save_component<C: Component + Clone>(world: &mut World) -> SavedState {
+    let mut query = world.query_filtered::<(Entity, &C), With<Rollback>>();
+    let saved: Vec<(Entity, C)> = query
+        .iter(world)
+        .map(|(entity, component)| (entity, component.clone()))
+        .collect();
+    Box::new(saved)
+}
+
+fn load_component<C: Component + Clone>(world: &mut World, saved: &SavedState) {
+    let Some(saved) = saved.downcast_ref::<Vec<(Entity, C)>>() else {
+        return;
+    };
+    let mut query = world.query_filtered::<Entity, (With<C>, With<Rollback>)>();
+    let stale: Vec<Entity> = query
+        .iter(world)
+        .filter(|entity| !saved.iter().any(|(saved_entity, _)| saved_entity == entity))
+        .collect();
+    for entity in stale {
+        world.entity_mut(entity).remove::<C>();
+    }
+    for (entity, component) in saved.iter() {
+        if let Some(mut entity) = world.get_entity_mut(*entity) {
+            entity.insert(component.clone());
+        }
+    }
+}
+
+fn save_resource<R: Resource + Clone>(world: &mut World) -> SavedState {
+    Box::new(world.get_resource::<R>().cloned())
+}
+
+fn load_resource<R: Resource + Clone>(world: &mut World, saved: &SavedState) {
+    if let Some(Some(resource)) = saved.downcast_ref::<Option<R>>() {
+        world.insert_resource(resource.clone());
+    }
+}
+
+/// Rapier's side of the world: the colliders it knows and the contacts between them, which
+/// decide what collision events the next step reports.
+struct PhysicsSnapshot {
+    islands: IslandManager,
+    broad_phase: BroadPhase,
+    narrow_phase: NarrowPhase,
+    bodies: RigidBodySet,
+    colliders: ColliderSet,
+    query_pipeline: QueryPipeline,
+}
+
+impl PhysicsSnapshot {
+    fn save(context: &RapierContext) -> Self {
+        Self {
+            islands: context.islands.clone(),
+            broad_phase: context.broad_phase.clone(),
+            narrow_phase: context.narrow_phase.clone(),
+            bodies: context.bodies.clone(),
+            colliders: context.colliders.clone(),
+            query_pipeline: context.query_pipeline.clone(),
+        }
+    }
+
+    /// The colliders of `respawned` entities are dropped again: their handle components
+    /// are gone, so Rapier builds them afresh from their `Collider` on the next step.
+    fn restore(&self, context: &mut RapierContext, respawned: &[Entity]) {
+        context.islands = self.islands.clone();
+        context.broad_phase = self.broad_phase.clone();
+        context.narrow_phase = self.narrow_phase.clone();
+        context.bodies = self.bodies.clone();
+        context.colliders = self.colliders.clone();
+        context.query_pipeline = self.query_pipeline.clone();
+
+        let orphans: Vec<_> = context
+            .colliders
+            .iter()
+            .filter(|(_, collider)| {
+                respawned
+                    .iter()
+                    .any(|entity| entity.to_bits() as u128 == collider.user_data)
+            })
+            .map(|(handle, _)| handle)
+            .collect();
+        let RapierContext {
+            islands,
+            bodies,
+            colliders,
+            ..
+        } = context;
+        for handle in orphans {
+            colliders.remove(handle, islands, bodies, false);
+        }
+    }
+}
+
+/// The registered state of the world before a frame, including Rapier's, so contacts that
+/// were already reported are not reported again after a rollback.
+pub struct Snapshot {
+    entities: Vec<Entity>,
+    components: Vec<SavedState>,
+    resources: Vec<SavedState>,
+    physics: Option<PhysicsSnapshot>,
+}
+
+impl Snapshot {
+    pub fn save(world: &mut World) -> Self {
+        let registry = world.resource::<RollbackRegistry>();
+        let component_fns = registry.components.clone();
+        let resource_fns = registry.resources.clone();
+        let mut query = world.query_filtered::<Entity, With<Rollback>>();
+        Self {
+            entities: query.iter(world).collect(),
+            components: component_fns.iter().map(|fns| (fns.save)(world)).collect(),
+            resources: resource_fns.iter().map(|fns| (fns.save)(world)).collect(),
+            physics: world
+                .get_resource::<RapierContext>()
+                .map(PhysicsSnapshot::save),
+        }
+    }
+
+    /// Despawns rollback entities created after the snapshot, respawns the ones destroyed
+    /// since, then writes back every registered component and resource and Rapier's state.
+    pub fn restore(&self, world: &mut World) {
+        let mut query = world.query_filtered::<Entity, With<Rollback>>();
+        let live: Vec<Entity> = query.iter(world).collect();
+        for entity in live.iter() {
+            if !self.entities.contains(entity) {
+                world.despawn(*entity);
+            }
+        }
+        if self.physics.is_some() {
+            // Rapier can only match despawned entities to their colliders before its state
+            // is replaced
+            world.run_system_once(sync_removals);
+        }
+        let mut respawned = Vec::new();
+        for entity in self.entities.iter() {
+            if world.get_entity(*entity).is_none() {
+                if let Some(mut entity_mut) = world.get_or_spawn(*entity) {
+                    entity_mut.insert(Rollback);
+                    respawned.push(*entity);
+                }
+            }
+        }
+
+        let registry = world.resource::<RollbackRegistry>();
+        let component_fns = registry.components.clone();
+        let resource_fns = registry.resources.clone();
+        for (fns, saved) in component_fns.iter().zip(self.components.iter()) {
+            (fns.load)(world, saved);
+        }
+        for (fns, saved) in resource_fns.iter().zip(self.resources.iter()) {
+            (fns.load)(world, saved);
+        }
+        if let (Some(physics), Some(mut context)) =
+            (&self.physics, world.get_resource_mut::<RapierContext>())
+        {
+            physics.restore(&mut context, &respawned);
+        }
+    }
+}
+
+const INPUT_BITS: [PlayerInput; 6] = [
+    PlayerInput::Left,
+    PlayerInput::Right,
+    PlayerInput::Up,
+    PlayerInput::Down,
+    PlayerInput::Attack,
+    PlayerInput::ResetLevel,
+];
+
+fn input_mask(input: &HashSet<PlayerInput>) -> u8 {
+    INPUT_BITS
+        .iter()
+        .enumerate()
+        .filter(|(_, action)| input.contains(action))
+        .fold(0, |mask, (bit, _)| mask | (1 << bit))
+}
+
+fn input_from_mask(mask: u8) -> HashSet<PlayerInput> {
+    INPUT_BITS
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| mask & (1 << bit) != 0)
+        .map(|(_, action)| *action)
+        .collect()
+}
+
+/// First byte of every packet, telling inputs and hellos apart.
+const INPUT_PACKET: u8 = 0;
+const HELLO_PACKET: u8 = 1;
+
+/// The last few input masks of one player, ending at `last_frame`.
+struct InputPacket {
+    player: PlayerId,
+    last_frame: u64,
+    masks: Vec<u8>,
+}
+
+impl InputPacket {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(11 + self.masks.len());
+        bytes.push(INPUT_PACKET);
+        bytes.push(self.player.0 as u8);
+        bytes.extend_from_slice(&self.last_frame.to_le_bytes());
+        bytes.push(self.masks.len() as u8);
+        bytes.extend_from_slice(&self.masks);
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let player = PlayerId(*bytes.first()? as usize);
+        let last_frame = u64::from_le_bytes(bytes.get(1..9)?.try_into().ok()?);
+        let count = *bytes.get(9)? as usize;
+        let masks = bytes.get(10..10 + count)?.to_vec();
+        if count as u64 > last_frame {
+            return None;
+        }
+        Some(Self {
+            player,
+            last_frame,
+            masks,
+        })
+    }
+
+    fn inputs(&self) -> impl Iterator<Item = (u64, u8)> + '_ {
+        let first_frame = self.last_frame + 1 - self.masks.len() as u64;
+        self.masks
+            .iter()
+            .enumerate()
+            .map(move |(offset, mask)| (first_frame + offset as u64, *mask))
+    }
+}
+
+/// What each peer sends until the other has started, so both begin on the same level with
+/// the same settings and fighters. The peer in the first slot hosts: the other takes on its
+/// settings and level.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Hello {
+    player: PlayerId,
+    level: String,
+    /// Roster name of the fighter the sender picked for its own slot.
+    character: String,
+    settings: SimulationConfig,
+}
+
+impl Hello {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![HELLO_PACKET];
+        match ron::to_string(self) {
+            Ok(text) => bytes.extend_from_slice(text.as_bytes()),
+            Err(err) => error!("could not encode netplay hello: {}", err),
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        ron::de::from_bytes(bytes).ok()
+    }
+}
+
+/// Sends each new input together with the previous ones so a dropped packet is covered by
+/// the next.
+#[derive(Default)]
+struct InputSender {
+    history: VecDeque<(u64, u8)>,
+}
+
+impl InputSender {
+    fn send(&mut self, transport: &mut dyn Transport, player: PlayerId, frame: u64, mask: u8) {
+        self.history.push_back((frame, mask));
+        while self.history.len() > MAX_ROLLBACK_FRAMES as usize {
+            self.history.pop_front();
+        }
+        let packet = InputPacket {
+            player,
+            last_frame: frame,
+            masks: self.history.iter().map(|(_, mask)| *mask).collect(),
+        };
+        transport.send(&packet.encode());
+    }
+}
+
+/// A two-player netplay session: `local` is read from this machine's devices, `remote`
+/// arrives over `transport` and is predicted until it does.
+#[derive(Resource)]
+pub struct RollbackSession {
+    pub local: PlayerId,
+    pub remote: PlayerId,
+    transport: Box<dyn Transport>,
+    sender: InputSender,
+    local_inputs: BTreeMap<u64, u8>,
+    remote_inputs: BTreeMap<u64, u8>,
+    predicted: BTreeMap<u64, u8>,
+    /// State right before the keyed frame was simulated.
+    snapshots: BTreeMap<u64, Snapshot>,
+    /// Newest frame simulated with fresh local input.
+    latest_frame: u64,
+    /// Every remote input up to and including this frame has arrived.
+    confirmed_frame: u64,
+    stalled: bool,
+    remote_hello: Option<Hello>,
+    /// The host's settings and level and the remote pick have been taken on.
+    agreed: bool,
+    /// Both peers agree and the players are ready, so the simulation may run.
+    started: bool,
+}
+
+impl RollbackSession {
+    pub fn new(local: PlayerId, remote: PlayerId, transport: Box<dyn Transport>) -> Self {
+        Self {
+            local,
+            remote,
+            transport,
+            sender: InputSender::default(),
+            local_inputs: BTreeMap::new(),
+            remote_inputs: BTreeMap::new(),
+            predicted: BTreeMap::new(),
+            snapshots: BTreeMap::new(),
+            latest_frame: 0,
+            confirmed_frame: 0,
+            stalled: false,
+            remote_hello: None,
+            agreed: false,
+            started: false,
+        }
+    }
+
+    /// Both peers know each other's picks, so players may get their characters.
+    pub fn agreed(&self) -> bool {
+        self.agreed
+    }
+
+    /// Newest frame no rollback can go back past any more.
+    pub fn confirmed_frame(&self) -> u64 {
+        self.confirmed_frame
+    }
+
+    /// Repeat the most recent confirmed input, the usual GGPO guess.
+    fn predict(&self, frame: u64) -> u8 {
+        self.remote_inputs
+            .range(..=frame)
+            .next_back()
+            .map(|(_, mask)| *mask)
+            .unwrap_or_default()
+    }
+
+    /// Stores newly arrived remote inputs and hellos, and returns the earliest frame that
+    /// was simulated with a wrong prediction.
+    fn receive(&mut self) -> Option<u64> {
+        let mut mispredicted: Option<u64> = None;
+        for bytes in self.transport.receive() {
+            let packet = match bytes.split_first() {
+                Some((&INPUT_PACKET, payload)) => InputPacket::decode(payload),
+                Some((&HELLO_PACKET, payload)) => {
+                    match Hello::decode(payload) {
+                        Some(hello) if hello.player == self.remote => {
+                            self.remote_hello = Some(hello);
+                        }
+                        Some(_) => {}
+                        None => warn!("dropping malformed netplay hello"),
+                    }
+                    continue;
+                }
+                _ => None,
+            };
+            let Some(packet) = packet else {
+                warn!("dropping malformed netplay packet");
+                continue;
+            };
+            if packet.player != self.remote {
+                continue;
+            }
+            for (frame, mask) in packet.inputs() {
+                if frame <= self.confirmed_frame || self.remote_inputs.contains_key(&frame) {
+                    continue;
+                }
+                self.remote_inputs.insert(frame, mask);
+                if self
+                    .predicted
+                    .get(&frame)
+                    .is_some_and(|guess| *guess != mask)
+                {
+                    mispredicted = Some(mispredicted.map_or(frame, |earliest| earliest.min(frame)));
+                }
+            }
+        }
+        while self.remote_inputs.contains_key(&(self.confirmed_frame + 1)) {
+            self.confirmed_frame += 1;
+        }
+        mispredicted
+    }
+
+    /// Drops history that can no longer be rolled back to.
+    fn prune(&mut self) {
+        let confirmed = self.confirmed_frame;
+        self.snapshots.retain(|frame, _| *frame > confirmed);
+        self.local_inputs.retain(|frame, _| *frame > confirmed);
+        self.predicted.retain(|frame, _| *frame > confirmed);
+        // keep the newest confirmed input around as the basis for predictions
+        self.remote_inputs.retain(|frame, _| *frame >= confirmed);
+    }
+}
+
+/// Drives the "remote" player of a loopback session from this machine's devices, so
+/// netplay can be exercised with simulated latency and loss on one machine.
+#[derive(Resource)]
+pub struct LoopbackPeer {
+    pub player: PlayerId,
+    transport: LoopbackTransport,
+    sender: InputSender,
+}
+
+pub struct RollbackPlugin;
+
+impl Plugin for RollbackPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RollbackRegistry>()
+            .init_resource::<Resimulating>()
+            .rollback_component::<Transform>()
+            .rollback_component::<GlobalTransform>()
+            .configure_sets(
+                FixedUpdate,
+                (
+                    GameSet::Input,
+                    GameSet::Player,
+                    GameSet::Combat,
+                    GameSet::Movement,
+                    GameSet::Physics,
+                )
+                    .run_if(session_started),
+            )
+            .add_systems(PreUpdate, rollback)
+            .add_systems(
+                Update,
+                (
+                    forget_history_on_spawn,
+                    exchange_hello.run_if(resource_exists::<RollbackSession>()),
+                ),
+            )
+            .add_systems(
+                FixedUpdate,
+                (
+                    save_snapshot.before(advance_frame),
+                    (loopback_peer_input, rollback_inputs)
+                        .chain()
+                        .after(InputSet::Collect)
+                        .before(InputSet::Write),
+                )
+                    .in_set(GameSet::Input)
+                    .run_if(resource_exists::<RollbackSession>()),
+            );
+
+        let args: Vec<String> = std::env::args().collect();
+        let Some(index) = args.iter().position(|arg| arg == "--netplay") else {
+            return;
+        };
+        let options: Vec<&str> = args[index + 1..].iter().map(String::as_str).collect();
+        match options.as_slice() {
+            ["loopback", rest @ ..] => {
+                let conditions = LinkConditions {
+                    delay: Duration::from_millis(
+                        rest.first().and_then(|ms| ms.parse().ok()).unwrap_or(0),
+                    ),
+                    packet_loss: rest
+                        .get(1)
+                        .and_then(|percent| percent.parse::<f32>().ok())
+                        .map_or(0.0, |percent| percent / 100.0),
+                };
+                info!("netplay over loopback with {:?}", conditions);
+                let (local, remote) = LoopbackTransport::pair(conditions);
+                app.insert_resource(RollbackSession::new(
+                    PlayerId(0),
+                    PlayerId(1),
+                    Box::new(local),
+                ))
+                .insert_resource(LoopbackPeer {
+                    player: PlayerId(1),
+                    transport: remote,
+                    sender: InputSender::default(),
+                });
+            }
+            ["udp", bind, peer, slot, ..] => {
+                let (Ok(bind), Ok(peer), Ok(slot)) = (
+                    bind.parse::<SocketAddr>(),
+                    peer.parse::<SocketAddr>(),
+                    slot.parse::<usize>(),
+                ) else {
+                    error!("usage: --netplay udp <bind address> <peer address> <slot 1|2>");
+                    return;
+                };
+                let local = PlayerId(slot.clamp(1, 2) - 1);
+                let remote = PlayerId(1 - local.0);
+                match UdpTransport::new(bind, peer) {
+                    Ok(transport) => {
+                        info!("netplay over udp {} <-> {} as {:?}", bind, peer, local);
+                        app.insert_resource(RollbackSession::new(
+                            local,
+                            remote,
+                            Box::new(transport),
+                        ));
+                    }
+                    Err(err) => error!("could not open udp socket on {}: {}", bind, err),
+                }
+            }
+            _ => error!(
+                "usage: --netplay loopback [delay ms] [loss %] | --netplay udp <bind> <peer> <slot>"
+            ),
+        }
+    }
+}
+
+/// Netplay holds the simulation until the peers have agreed on how to run it.
+fn session_started(session: Option<Res<RollbackSession>>) -> bool {
+    session.is_none_or(|session| session.started)
+}
+
+/// The level and the fighters, which the peers agree on besides the settings.
+#[derive(SystemParam)]
+struct MatchSetup<'w, 's> {
+    level_selection: ResMut<'w, LevelSelection>,
+    character_selection: ResMut<'w, CharacterSelection>,
+    roster: Res<'w, CharacterRoster>,
+    ldtk_handle: Res<'w, LdtkProjectHandle>,
+    ldtk_projects: Res<'w, Assets<LdtkProject>>,
+    level_query: Query<'w, 's, &'static LevelIid>,
+    player_query: Query<'w, 's, Has<Character>, With<Player>>,
+}
+
+impl MatchSetup<'_, '_> {
+    fn level(&self) -> Option<String> {
+        let project = self.ldtk_projects.get(&self.ldtk_handle.0)?;
+        selected_level_iid(&self.level_selection, project)
+    }
+
+    /// Roster name of the fighter picked for `player`, once the roster has loaded.
+    fn pick(&self, player: PlayerId) -> Option<String> {
+        let characters = &self.roster.characters;
+        if characters.is_empty() {
+            return None;
+        }
+        let index = self
+            .character_selection
+            .0
+            .get(player.0)
+            .copied()
+            .unwrap_or(0);
+        Some(characters[index % characters.len()].name.clone())
+    }
+
+    /// Picks the fighter named `character` for `player`, if it is in the roster.
+    fn set_pick(&mut self, player: PlayerId, character: &str) -> bool {
+        let Some(index) = self
+            .roster
+            .characters
+            .iter()
+            .position(|entry| entry.name == character)
+        else {
+            return false;
+        };
+        if let Some(slot) = self.character_selection.0.get_mut(player.0) {
+            *slot = index;
+        }
+        true
+    }
+
+    /// `level` has spawned and every player on it has its character.
+    fn ready(&self, level: &str) -> bool {
+        self.level_query.iter().any(|iid| iid.to_string() == level)
+            && !self.player_query.is_empty()
+            && self.player_query.iter().all(|has_character| has_character)
+    }
+}
+
+/// Sends this peer's `Hello` until the other peer's inputs arrive, which means it has
+/// started and so has heard it. Once the other `Hello` is in, takes on the host's settings
+/// and level and the remote pick, then starts when the players of the host's level have
+/// their characters here too.
+fn exchange_hello(
+    mut session: ResMut<RollbackSession>,
+    mut loopback_peer: Option<ResMut<LoopbackPeer>>,
+    mut settings: SimulationSettings,
+    mut setup: MatchSetup,
+    mut reported_unknown: Local<bool>,
+) {
+    let (Some(level), Some(character)) = (setup.level(), setup.pick(session.local)) else {
+        return;
+    };
+    let hello = Hello {
+        player: session.local,
+        level,
+        character,
+        settings: settings.get(),
+    };
+    if session.remote_inputs.is_empty() {
+        session.transport.send(&hello.encode());
+        if let Some(peer) = loopback_peer.as_mut() {
+            // the other end runs in this process, so it agrees on everything
+            let peer_hello = Hello {
+                player: peer.player,
+                character: setup.pick(peer.player).unwrap_or_default(),
+                ..hello.clone()
+            };
+            peer.transport.send(&peer_hello.encode());
+        }
+    }
+    if session.started {
+        return;
+    }
+    let Some(remote) = session.remote_hello.clone() else {
+        return;
+    };
+
+    let host = if session.local == PlayerId(0) {
+        hello.clone()
+    } else {
+        remote.clone()
+    };
+    if !session.agreed {
+        if !setup.set_pick(session.remote, &remote.character) {
+            if !*reported_unknown {
+                error!(
+                    "netplay peer picked {:?}, which is not in the roster",
+                    remote.character
+                );
+                *reported_unknown = true;
+            }
+            return;
+        }
+        if host.settings != hello.settings {
+            warn!(
+                "netplay peers disagree on settings, using the host's: {:?}",
+                host.settings
+            );
+            settings.set(host.settings);
+        }
+        if host.level != hello.level {
+            info!("netplay moves to the host's level {}", host.level);
+            *setup.level_selection = LevelSelection::iid(host.level.clone());
+        }
+        session.agreed = true;
+    }
+    if !setup.ready(&host.level) {
+        return;
+    }
+    info!("netplay session started");
+    session.started = true;
+}
+
+fn save_snapshot(world: &mut World) {
+    let frame = world.resource::<SimulationFrame>().0 + 1;
+    let snapshot = Snapshot::save(world);
+    world
+        .resource_mut::<RollbackSession>()
+        .snapshots
+        .insert(frame, snapshot);
+}
+
+fn loopback_peer_input(
+    frame: Res<SimulationFrame>,
+    session: Res<RollbackSession>,
+    peer: Option<ResMut<LoopbackPeer>>,
+    pending: Res<PendingInput>,
+) {
+    let Some(mut peer) = peer else {
+        return;
+    };
+    // resimulated frames were already sent
+    if frame.0 <= session.latest_frame {
+        return;
+    }
+    let mask = pending
+        .0
+        .get(&peer.player)
+        .map(input_mask)
+        .unwrap_or_default();
+    let LoopbackPeer {
+        player,
+        transport,
+        sender,
+    } = peer.as_mut();
+    sender.send(transport, *player, frame.0, mask);
+}
+
+fn rollback_inputs(
+    frame: Res<SimulationFrame>,
+    mut session: ResMut<RollbackSession>,
+    mut pending: ResMut<PendingInput>,
+) {
+    let frame = frame.0;
+    let local_mask = if frame <= session.latest_frame {
+        session
+            .local_inputs
+            .get(&frame)
+            .copied()
+            .unwrap_or_default()
+    } else {
+        let mask = pending
+            .0
+            .get(&session.local)
+            .map(input_mask)
+            .unwrap_or_default();
+        let RollbackSession {
+            local,
+            transport,
+            sender,
+            ..
+        } = session.as_mut();
+        sender.send(transport.as_mut(), *local, frame, mask);
+        session.local_inputs.insert(frame, mask);
+        session.latest_frame = frame;
+        mask
+    };
+    let remote_mask = match session.remote_inputs.get(&frame) {
+        Some(mask) => *mask,
+        None => {
+            let guess = session.predict(frame);
+            session.predicted.insert(frame, guess);
+            guess
+        }
+    };
+
+    pending.0.clear();
+    pending.0.insert(session.local, input_from_mask(local_mask));
+    pending
+        .0
+        .insert(session.remote, input_from_mask(remote_mask));
+}
+
+/// Players that spawned or got their character since the last frame.
+type NewPlayers = Or<(Added<Player>, Added<Character>)>;
+
+/// Players are spawned by the level and given their character outside the simulation, so
+/// older snapshots would undo that again.
+fn forget_history_on_spawn(
+    spawned: Query<(), NewPlayers>,
+    session: Option<ResMut<RollbackSession>>,
+) {
+    if let Some(mut session) = session {
+        if !spawned.is_empty() {
+            session.snapshots.clear();
+        }
+    }
+}
+
+/// Applies late remote inputs: restores the snapshot of the first mispredicted frame and
+/// resimulates up to the present, then stalls if prediction has run too far ahead.
+fn rollback(world: &mut World) {
+    let Some(mut session) = world.get_resource_mut::<RollbackSession>() else {
+        return;
+    };
+    let mispredicted = session.receive();
+    let latest_frame = session.latest_frame;
+
+    if let Some(frame) = mispredicted {
+        session.predicted.retain(|predicted, _| *predicted < frame);
+        let snapshot = session.snapshots.remove(&frame);
+        match snapshot {
+            Some(snapshot) => {
+                snapshot.restore(world);
+                world.resource_mut::<Resimulating>().0 = true;
+                for _ in frame..=latest_frame {
+                    world.run_schedule(FixedUpdate);
+                }
+                world.resource_mut::<Resimulating>().0 = false;
+            }
+            None => warn!("cannot roll back to frame {}: no snapshot", frame),
+        }
+    }
+
+    let mut session = world.resource_mut::<RollbackSession>();
+    session.prune();
+    let stall = session.latest_frame >= session.confirmed_frame + MAX_ROLLBACK_FRAMES;
+    if stall != session.stalled {
+        session.stalled = stall;
+        let mut time = world.resource_mut::<Time<Virtual>>();
+        if stall {
+            time.pause();
+        } else {
+            time.unpause();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bindings::InputBindings;
+    use crate::game_state::GameState;
+    use crate::hitstop::Hitstop;
+    use crate::input_buffer::InputBuffer;
+    use crate::motion::InputHistory;
+    use crate::movement::{InputState, MovementPlugin, Velocity};
+    use crate::player::{AttackHeight, Direction, Health};
+    use crate::player_state::{CurrentMove, Guard, PlayerState, StateTimer};
+    use crate::rounds::{MatchRules, MatchState, RoundsPlugin, SpawnPoint};
+    use crate::transport::LinkConditions;
+    use bevy_rapier2d::prelude::KinematicCharacterController;
+
+    /// Every tick the remote player holds attack takes 40 health off the local one.
+    fn hit_while_attacking(mut query: Query<(&PlayerId, &InputState, &mut Health)>) {
+        let attacking = query.iter().any(|(player_id, input, _)| {
+            *player_id == PlayerId(1) && input.pressed(PlayerInput::Attack)
+        });
+        for (player_id, _, mut health) in query.iter_mut() {
+            if attacking && *player_id == PlayerId(0) {
+                health.current -= 40.0;
+            }
+        }
+    }
+
+    /// A started loopback session between two bare players, driven one tick at a time,
+    /// and the remote end of its link.
+    fn netplay_app(rules: MatchRules) -> (App, LoopbackTransport) {
+        let (local, remote) = LoopbackTransport::pair(LinkConditions::default());
+        let mut session = RollbackSession::new(PlayerId(0), PlayerId(1), Box::new(local));
+        session.started = true;
+
+        let mut app = App::new();
+        app.add_state::<GameState>()
+            .init_resource::<Input<KeyCode>>()
+            .insert_resource(InputBindings {
+                players: Vec::new(),
+                stick_deadzone: 0.0,
+            })
+            .init_resource::<Time<Virtual>>()
+            .init_resource::<RollbackRegistry>()
+            .init_resource::<Resimulating>()
+            .init_resource::<SimulationFrame>()
+            .rollback_resource::<SimulationFrame>()
+            .rollback_component::<Health>()
+            .insert_resource(session)
+            .configure_sets(
+                FixedUpdate,
+                (
+                    GameSet::Input,
+                    GameSet::Player,
+                    GameSet::Combat,
+                    GameSet::Movement,
+                    GameSet::Physics,
+                )
+                    .chain(),
+            )
+            .add_plugins((MovementPlugin, RoundsPlugin))
+            .insert_resource(rules)
+            .add_systems(
+                FixedUpdate,
+                (
+                    save_snapshot.before(advance_frame),
+                    advance_frame.before(InputSet::Collect),
+                    rollback_inputs
+                        .after(InputSet::Collect)
+                        .before(InputSet::Write),
+                )
+                    .in_set(GameSet::Input),
+            )
+            .add_systems(FixedUpdate, hit_while_attacking.in_set(GameSet::Player));
+
+        for (slot, x) in [(0, -50.0), (1, 50.0)] {
+            let translation = Vec3::new(x, 0.0, 0.0);
+            app.world.spawn((
+                (Player, PlayerId(slot), Rollback, Health::default()),
+                (
+                    SpawnPoint {
+                        translation,
+                        direction: Direction::Right,
+                    },
+                    Transform::from_translation(translation),
+                    Velocity::default(),
+                    Direction::Right,
+                    PlayerState::default(),
+                    StateTimer::default(),
+                    CurrentMove::default(),
+                    AttackHeight::Normal,
+                    Guard::default(),
+                    Hitstop::default(),
+                ),
+                (
+                    InputState::default(),
+                    InputHistory::default(),
+                    InputBuffer::default(),
+                    KinematicCharacterController::default(),
+                ),
+            ));
+        }
+        app.world
+            .resource_mut::<NextState<GameState>>()
+            .set(GameState::Fight);
+        app.world.run_schedule(StateTransition);
+        (app, remote)
+    }
+
+    fn tick(app: &mut App) {
+        rollback(&mut app.world);
+        app.world.run_schedule(FixedUpdate);
+    }
+
+    fn local_health(app: &mut App) -> f32 {
+        let mut query = app.world.query::<(&PlayerId, &Health)>();
+        query
+            .iter(&app.world)
+            .find(|(player_id, _)| **player_id == PlayerId(0))
+            .map_or(0.0, |(_, health)| health.current)
+    }
+
+    #[test]
+    fn rollback_takes_back_a_knock_out_from_a_mispredicted_input() {
+        let (mut app, mut remote) = netplay_app(MatchRules::default());
+        let mut sender = InputSender::default();
+        let attack = input_mask(&HashSet::from([PlayerInput::Attack]));
+        for frame in 1..=2 {
+            sender.send(&mut remote, PlayerId(1), frame, attack);
+            tick(&mut app);
+        }
+        assert_eq!(local_health(&mut app), 20.0);
+
+        // the remote attack is predicted to go on, which knocks the local player out
+        tick(&mut app);
+        tick(&mut app);
+        let match_state = app.world.resource::<MatchState>();
+        assert!(match_state.round_over.is_some());
+        assert_eq!(match_state.results.len(), 1);
+
+        // it was let go instead
+        sender.send(&mut remote, PlayerId(1), 3, 0);
+        sender.send(&mut remote, PlayerId(1), 4, 0);
+        tick(&mut app);
+        let match_state = app.world.resource::<MatchState>();
+        assert!(match_state.round_over.is_none());
+        assert!(match_state.results.is_empty());
+        assert_eq!(match_state.wins, [0, 0]);
+        assert_eq!(local_health(&mut app), 20.0);
+        assert_eq!(app.world.resource::<SimulationFrame>().0, 5);
+    }
+
+    #[test]
+    fn match_ends_once_its_last_frame_is_confirmed() {
+        let (mut app, mut remote) = netplay_app(MatchRules {
+            best_of: 1,
+            round_seconds: 99,
+        });
+        let mut sender = InputSender::default();
+        let attack = input_mask(&HashSet::from([PlayerInput::Attack]));
+        for frame in 1..=3 {
+            sender.send(&mut remote, PlayerId(1), frame, attack);
+            tick(&mut app);
+        }
+        assert!(app.world.resource::<MatchState>().outcome.is_some());
+
+        // remote input trails a few frames behind through the pause after the round
+        let mut frame = 3;
+        while app.world.resource::<MatchState>().over_on.is_none() {
+            frame += 1;
+            sender.send(&mut remote, PlayerId(1), frame - 4, 0);
+            tick(&mut app);
+        }
+        assert_eq!(app.world.resource::<MatchState>().over_on, Some(frame));
+        app.world.run_schedule(Update);
+        app.world.run_schedule(StateTransition);
+        assert_eq!(
+            *app.world.resource::<State<GameState>>().get(),
+            GameState::Fight
+        );
+
+        for late in frame - 3..=frame {
+            sender.send(&mut remote, PlayerId(1), late, 0);
+        }
+        rollback(&mut app.world);
+        app.world.run_schedule(Update);
+        app.world.run_schedule(StateTransition);
+        assert_eq!(
+            *app.world.resource::<State<GameState>>().get(),
+            GameState::MatchOver
+        );
+    }
+}
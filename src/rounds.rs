@@ -0,0 +1,419 @@
+use crate::attack::Attack;
+use crate::game_state::GameState;
+use crate::hitstop::Hitstop;
+use crate::input_buffer::InputBuffer;
+use crate::motion::InputHistory;
+use crate::movement::{InputSet, Velocity};
+use crate::player::{AttackHeight, Direction, Health, Player, PlayerId, MAX_PLAYERS};
+use crate::player_state::{react_to_blocks, CurrentMove, Guard, PlayerState, StateTimer};
+use crate::rollback::{Resimulating, RollbackAppExt, RollbackSession};
+use crate::simulation::{FrameTimer, GameSet, SimulationFrame, TICKS_PER_SECOND};
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Pause between the end of a round and the next one, or the results screen.
+const ROUND_OVER_SECONDS: f32 = 2.0;
+
+/// How a match is played. Set with `--best-of <rounds>` and `--round-time <seconds>`.
+#[derive(Resource, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatchRules {
+    /// Most rounds a match can last; the first to win more than half of them takes it.
+    pub best_of: u32,
+    pub round_seconds: u32,
+}
+
+impl Default for MatchRules {
+    fn default() -> Self {
+        Self {
+            best_of: 3,
+            round_seconds: 99,
+        }
+    }
+}
+
+impl MatchRules {
+    pub fn rounds_to_win(&self) -> u32 {
+        self.best_of / 2 + 1
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RoundEnd {
+    KnockOut,
+    /// The player with more health left, relative to their maximum, wins.
+    TimeOut,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RoundResult {
+    pub round: u32,
+    /// `None` for a draw, which counts for neither player.
+    pub winner: Option<PlayerId>,
+    pub end: RoundEnd,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MatchOutcome {
+    Winner(PlayerId),
+    Draw,
+}
+
+/// Progress of the current match: the round being played, its timer and who won so far.
+#[derive(Resource, Clone, Debug)]
+pub struct MatchState {
+    /// One-based.
+    pub round: u32,
+    pub timer: FrameTimer,
+    /// Counts down the pause after the current round was decided; `None` while it is played.
+    pub round_over: Option<FrameTimer>,
+    pub wins: [u32; MAX_PLAYERS],
+    pub results: Vec<RoundResult>,
+    pub outcome: Option<MatchOutcome>,
+    /// Frame the pause after the deciding round ran out on.
+    pub over_on: Option<u64>,
+}
+
+impl MatchState {
+    pub fn new(rules: &MatchRules) -> Self {
+        Self {
+            round: 1,
+            timer: FrameTimer::from_seconds(rules.round_seconds as f32),
+            round_over: None,
+            wins: [0; MAX_PLAYERS],
+            results: Vec::new(),
+            outcome: None,
+            over_on: None,
+        }
+    }
+
+    /// Whole seconds left on the round timer, rounded up.
+    pub fn seconds_left(&self) -> u32 {
+        (self.timer.duration - self.timer.elapsed).div_ceil(TICKS_PER_SECOND)
+    }
+}
+
+#[derive(Event, Copy, Clone, Debug)]
+pub struct RoundStartEvent {
+    pub round: u32,
+}
+
+#[derive(Event, Copy, Clone, Debug)]
+pub struct RoundEndEvent(pub RoundResult);
+
+#[derive(Event, Copy, Clone, Debug)]
+pub struct MatchEndEvent(pub MatchOutcome);
+
+/// Where the level placed a player, which every new round puts them back to.
+#[derive(Component, Copy, Clone, Debug)]
+pub struct SpawnPoint {
+    pub translation: Vec3,
+    pub direction: Direction,
+}
+
+pub struct RoundsPlugin;
+
+impl Plugin for RoundsPlugin {
+    fn build(&self, app: &mut App) {
+        let args: Vec<String> = std::env::args().collect();
+        let arg = |name: &str| {
+            args.iter()
+                .position(|arg| arg == name)
+                .and_then(|index| args.get(index + 1))
+                .and_then(|value| value.parse::<u32>().ok())
+        };
+        let defaults = MatchRules::default();
+        let rules = MatchRules {
+            best_of: arg("--best-of").unwrap_or(defaults.best_of).max(1),
+            round_seconds: arg("--round-time").unwrap_or(defaults.round_seconds).max(1),
+        };
+
+        app.insert_resource(rules)
+            .insert_resource(MatchState::new(&rules))
+            .rollback_resource::<MatchState>()
+            .add_event::<RoundStartEvent>()
+            .add_event::<RoundEndEvent>()
+            .add_event::<MatchEndEvent>()
+            .configure_sets(
+                FixedUpdate,
+                (
+                    GameSet::Player,
+                    GameSet::Combat,
+                    GameSet::Movement,
+                    GameSet::Physics,
+                )
+                    .run_if(round_in_play),
+            )
+            .add_systems(OnEnter(GameState::Title), start_match)
+            .add_systems(
+                Update,
+                (
+                    record_spawn_points,
+                    end_match.run_if(in_state(GameState::Fight)),
+                    log_round_start,
+                ),
+            )
+            .add_systems(
+                FixedUpdate,
+                finish_round.after(InputSet::Write).in_set(GameSet::Input),
+            )
+            .add_systems(
+                FixedUpdate,
+                (update_round, log_round_end)
+                    .chain()
+                    .after(react_to_blocks)
+                    .in_set(GameSet::Combat),
+            );
+    }
+}
+
+/// The players only act while the round is undecided; the pause after it keeps them still.
+fn round_in_play(match_state: Res<MatchState>) -> bool {
+    match_state.round_over.is_none()
+}
+
+fn start_match(
+    rules: Res<MatchRules>,
+    mut match_state: ResMut<MatchState>,
+    mut ev_round_start: EventWriter<RoundStartEvent>,
+) {
+    *match_state = MatchState::new(&rules);
+    ev_round_start.send(RoundStartEvent { round: 1 });
+}
+
+fn record_spawn_points(
+    mut commands: Commands,
+    query: Query<(Entity, &Transform, &Direction), Added<Player>>,
+) {
+    for (entity, transform, direction) in query.iter() {
+        commands.entity(entity).insert(SpawnPoint {
+            translation: transform.translation,
+            direction: *direction,
+        });
+    }
+}
+
+/// Ticks the round timer and decides the round once someone is knocked out or time runs
+/// out. Runs in the simulation so every peer and replay ends the round on the same tick,
+/// and a rollback can take back a round decided by mispredicted input.
+fn update_round(
+    rules: Res<MatchRules>,
+    mut match_state: ResMut<MatchState>,
+    player_query: Query<(&PlayerId, &Health), With<Player>>,
+    mut ev_round_end: EventWriter<RoundEndEvent>,
+    mut ev_match_end: EventWriter<MatchEndEvent>,
+) {
+    if player_query.is_empty() {
+        return;
+    }
+    match_state.timer.tick();
+
+    let mut players: Vec<(PlayerId, f32)> = player_query
+        .iter()
+        .map(|(player_id, health)| (*player_id, health.current / health.max))
+        .collect();
+    players.sort_by_key(|(player_id, _)| *player_id);
+    let end = if players.iter().any(|(_, health)| *health <= 0.0) {
+        RoundEnd::KnockOut
+    } else if match_state.timer.finished() {
+        RoundEnd::TimeOut
+    } else {
+        return;
+    };
+
+    // a double KO or equal health at time out leaves more than one leader
+    let best = players
+        .iter()
+        .map(|(_, health)| *health)
+        .fold(f32::MIN, f32::max);
+    let leaders: Vec<PlayerId> = players
+        .iter()
+        .filter(|(_, health)| *health == best)
+        .map(|(player_id, _)| *player_id)
+        .collect();
+    let winner = match leaders.as_slice() {
+        [winner] => Some(*winner),
+        _ => None,
+    };
+
+    let result = RoundResult {
+        round: match_state.round,
+        winner,
+        end,
+    };
+    if let Some(winner) = winner {
+        match_state.wins[winner.0] += 1;
+    }
+    match_state.results.push(result);
+    match_state.round_over = Some(FrameTimer::from_seconds(ROUND_OVER_SECONDS));
+    ev_round_end.send(RoundEndEvent(result));
+
+    let champion = (0..MAX_PLAYERS).find(|slot| match_state.wins[*slot] >= rules.rounds_to_win());
+    let outcome = if let Some(slot) = champion {
+        Some(MatchOutcome::Winner(PlayerId(slot)))
+    } else if match_state.round >= rules.best_of {
+        // out of rounds after draws: most wins takes it, a tie draws the match
+        let most = match_state.wins.iter().copied().max().unwrap_or(0);
+        let mut leaders = (0..MAX_PLAYERS).filter(|slot| match_state.wins[*slot] == most);
+        match (leaders.next(), leaders.next()) {
+            (Some(slot), None) => Some(MatchOutcome::Winner(PlayerId(slot))),
+            _ => Some(MatchOutcome::Draw),
+        }
+    } else {
+        None
+    };
+    if let Some(outcome) = outcome {
+        match_state.outcome = Some(outcome);
+        ev_match_end.send(MatchEndEvent(outcome));
+    }
+}
+
+/// Shows the results once the match ended on a frame every remote input has arrived for,
+/// so no rollback can take the deciding round back any more.
+fn end_match(
+    match_state: Res<MatchState>,
+    session: Option<Res<RollbackSession>>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    let Some(over_on) = match_state.over_on else {
+        return;
+    };
+    if session.is_some_and(|session| session.confirmed_frame() < over_on) {
+        return;
+    }
+    next_state.set(GameState::MatchOver);
+}
+
+/// Logs each round start once, not again when a rollback starts the round anew. The first
+/// round is started outside the simulation, by every new match.
+fn log_round_start(
+    match_state: Res<MatchState>,
+    mut ev_round_start: EventReader<RoundStartEvent>,
+    mut logged: Local<u32>,
+) {
+    for start in ev_round_start.read() {
+        if start.round != 1 && start.round <= *logged {
+            continue;
+        }
+        *logged = start.round;
+        info!(
+            "round {} starts with {} seconds on the clock",
+            start.round,
+            match_state.seconds_left()
+        );
+    }
+}
+
+/// Logs each decided round and match once, not again when a rollback decides it anew.
+fn log_round_end(
+    resimulating: Res<Resimulating>,
+    mut ev_round_end: EventReader<RoundEndEvent>,
+    mut ev_match_end: EventReader<MatchEndEvent>,
+) {
+    if resimulating.0 {
+        ev_round_end.clear();
+        ev_match_end.clear();
+        return;
+    }
+    for RoundEndEvent(result) in ev_round_end.read() {
+        info!(
+            "round {} ended by {:?}, winner {:?}",
+            result.round, result.end, result.winner
+        );
+    }
+    for MatchEndEvent(outcome) in ev_match_end.read() {
+        info!("match over: {:?}", outcome);
+    }
+}
+
+/// Everything a new round puts back the way it was at the start of the match.
+type RoundReset<'a> = (
+    &'a SpawnPoint,
+    &'a mut Transform,
+    &'a mut Velocity,
+    &'a mut Health,
+    &'a mut Direction,
+    &'a mut PlayerState,
+    &'a mut StateTimer,
+    &'a mut CurrentMove,
+    &'a mut AttackHeight,
+    &'a mut Guard,
+    &'a mut Hitstop,
+    &'a mut InputHistory,
+    &'a mut InputBuffer,
+    &'a mut KinematicCharacterController,
+    Option<&'a mut KinematicCharacterControllerOutput>,
+);
+
+/// Counts down the round over pause. Once it runs out, notes the frame a decided match
+/// ended on, or puts the players back at their spawn points for the next round. Runs in the
+/// simulation, so a rollback replays it like any other tick. The level itself is left as it
+/// is.
+fn finish_round(
+    mut commands: Commands,
+    frame: Res<SimulationFrame>,
+    rules: Res<MatchRules>,
+    mut match_state: ResMut<MatchState>,
+    mut player_query: Query<RoundReset, With<Player>>,
+    attack_query: Query<Entity, With<Attack>>,
+    mut ev_round_start: EventWriter<RoundStartEvent>,
+) {
+    let Some(pause) = match_state.round_over.as_mut() else {
+        return;
+    };
+    pause.tick();
+    if !pause.finished() || match_state.over_on.is_some() {
+        return;
+    }
+    // no attack may outlive the round it was thrown in
+    for entity in attack_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    if match_state.outcome.is_some() {
+        match_state.over_on = Some(frame.0);
+        return;
+    }
+
+    for (
+        spawn_point,
+        mut transform,
+        mut velocity,
+        mut health,
+        mut direction,
+        mut state,
+        mut state_timer,
+        mut current_move,
+        mut attack_height,
+        mut guard,
+        mut hitstop,
+        mut history,
+        mut buffer,
+        mut controller,
+        controller_output,
+    ) in player_query.iter_mut()
+    {
+        transform.translation = spawn_point.translation;
+        velocity.velocity = Vec2::ZERO;
+        health.current = health.max;
+        *direction = spawn_point.direction;
+        *state = PlayerState::Idle;
+        *state_timer = StateTimer::default();
+        *current_move = CurrentMove::default();
+        *attack_height = AttackHeight::Normal;
+        *guard = Guard::default();
+        *hitstop = Hitstop::default();
+        *history = InputHistory::default();
+        *buffer = InputBuffer::default();
+        controller.translation = None;
+        // the last move's ground contact would otherwise decide the first tick of the round
+        if let Some(mut output) = controller_output {
+            *output = KinematicCharacterControllerOutput::default();
+        }
+    }
+    match_state.round += 1;
+    match_state.timer = FrameTimer::from_seconds(rules.round_seconds as f32);
+    match_state.round_over = None;
+    ev_round_start.send(RoundStartEvent {
+        round: match_state.round,
+    });
+}
@@ -0,0 +1,168 @@
+use crate::game_state::GameState;
+use crate::hitstop::ReducedMotion;
+use crate::input_buffer::BufferSettings;
+use crate::motion::MotionLeniency;
+use crate::movement::InputSet;
+use crate::player_state::KnockbackScaling;
+use crate::rollback::RollbackAppExt;
+use crate::rounds::MatchRules;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Simulation rate of every gameplay system.
+pub const TICKS_PER_SECOND: u32 = 60;
+/// Length of one simulation tick in seconds.
+pub const TIMESTEP: f32 = 1.0 / TICKS_PER_SECOND as f32;
+
+/// Gameplay stages inside `FixedUpdate`, run in this order every tick while fighting.
+#[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone)]
+pub enum GameSet {
+    /// Sample devices and write this tick's `InputState` of every player.
+    Input,
+    /// Consume input and apply per-player rules.
+    Player,
+    /// Spawn attacks and resolve hits.
+    Combat,
+    /// Turn velocities into character controller movement.
+    Movement,
+    /// Rapier step.
+    Physics,
+}
+
+/// Number of simulation ticks run so far, counting the current one. Advanced at the start of
+/// every tick, so it stands still outside `GameState::Fight` and is restored along with the
+/// rest of the simulation on rollback; it is not wall-clock time.
+#[derive(Resource, Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SimulationFrame(pub u64);
+
+/// A `Timer` counted in simulation ticks instead of seconds, so it advances identically no
+/// matter the render frame rate.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameTimer {
+    pub duration: u32,
+    pub elapsed: u32,
+}
+
+impl FrameTimer {
+    pub fn new(duration: u32) -> Self {
+        Self {
+            duration,
+            elapsed: 0,
+        }
+    }
+
+    pub fn from_seconds(seconds: f32) -> Self {
+        Self::new((seconds * TICKS_PER_SECOND as f32).round() as u32)
+    }
+
+    pub fn tick(&mut self) {
+        self.elapsed = (self.elapsed + 1).min(self.duration);
+    }
+
+    pub fn finished(&self) -> bool {
+        self.elapsed >= self.duration
+    }
+}
+
+/// Every setting that changes the outcome of the simulation, as recorded in replays.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SimulationConfig {
+    pub knockback_scaling: KnockbackScaling,
+    pub rules: MatchRules,
+    /// Whether hitstop is off.
+    pub reduced_motion: bool,
+    pub motion_leniency: MotionLeniency,
+    pub input_buffer: BufferSettings,
+}
+
+/// The resources making up `SimulationConfig`, read and replaced together.
+#[derive(SystemParam)]
+pub struct SimulationSettings<'w> {
+    knockback_scaling: ResMut<'w, KnockbackScaling>,
+    rules: ResMut<'w, MatchRules>,
+    reduced_motion: ResMut<'w, ReducedMotion>,
+    motion_leniency: ResMut<'w, MotionLeniency>,
+    input_buffer: ResMut<'w, BufferSettings>,
+}
+
+impl SimulationSettings<'_> {
+    pub fn get(&self) -> SimulationConfig {
+        SimulationConfig {
+            knockback_scaling: *self.knockback_scaling,
+            rules: *self.rules,
+            reduced_motion: self.reduced_motion.0,
+            motion_leniency: *self.motion_leniency,
+            input_buffer: *self.input_buffer,
+        }
+    }
+
+    pub fn set(&mut self, config: SimulationConfig) {
+        *self.knockback_scaling = config.knockback_scaling;
+        *self.rules = config.rules;
+        self.reduced_motion.0 = config.reduced_motion;
+        *self.motion_leniency = config.motion_leniency;
+        *self.input_buffer = config.input_buffer;
+    }
+}
+
+pub struct SimulationPlugin;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Time::<Fixed>::from_seconds(TIMESTEP as f64))
+            .insert_resource(RapierConfiguration {
+                timestep_mode: TimestepMode::Fixed {
+                    dt: TIMESTEP,
+                    substeps: 1,
+                },
+                ..Default::default()
+            })
+            .init_resource::<SimulationFrame>()
+            .rollback_resource::<SimulationFrame>()
+            .configure_sets(
+                FixedUpdate,
+                (
+                    GameSet::Input,
+                    GameSet::Player,
+                    GameSet::Combat,
+                    GameSet::Movement,
+                    GameSet::Physics,
+                )
+                    .chain()
+                    .run_if(in_state(GameState::Fight)),
+            )
+            .configure_sets(
+                FixedUpdate,
+                (
+                    PhysicsSet::SyncBackend,
+                    PhysicsSet::StepSimulation,
+                    PhysicsSet::Writeback,
+                )
+                    .chain()
+                    .in_set(GameSet::Physics),
+            )
+            .add_systems(
+                FixedUpdate,
+                (
+                    RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::SyncBackend)
+                        .in_set(PhysicsSet::SyncBackend),
+                    RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::StepSimulation)
+                        .in_set(PhysicsSet::StepSimulation),
+                    RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::Writeback)
+                        .in_set(PhysicsSet::Writeback),
+                ),
+            )
+            .add_systems(
+                FixedUpdate,
+                advance_frame
+                    .in_set(GameSet::Input)
+                    .before(InputSet::Collect),
+            );
+    }
+}
+
+pub fn advance_frame(mut frame: ResMut<SimulationFrame>) {
+    frame.0 += 1;
+}
@@ -0,0 +1,132 @@
+use bevy::log::warn;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Moves opaque packets between two peers. Delivery is unreliable and unordered, like UDP;
+/// the rollback layer resends recent inputs in every packet to cover for drops.
+pub trait Transport: Send + Sync + 'static {
+    fn send(&mut self, packet: &[u8]);
+    /// Every packet that has arrived since the last call.
+    fn receive(&mut self) -> Vec<Vec<u8>>;
+}
+
+const MAX_PACKET_SIZE: usize = 1024;
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    peer: SocketAddr,
+}
+
+impl UdpTransport {
+    pub fn new(local: SocketAddr, peer: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, peer })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, packet: &[u8]) {
+        if let Err(err) = self.socket.send_to(packet, self.peer) {
+            warn!("udp send to {} failed: {}", self.peer, err);
+        }
+    }
+
+    fn receive(&mut self) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((size, from)) if from == self.peer => packets.push(buffer[..size].to_vec()),
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    warn!("udp receive failed: {}", err);
+                    break;
+                }
+            }
+        }
+        packets
+    }
+}
+
+/// Simulated network conditions applied by `LoopbackTransport`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LinkConditions {
+    pub delay: Duration,
+    /// Chance in 0.0..=1.0 that a packet is dropped.
+    pub packet_loss: f32,
+}
+
+type PacketQueue = Arc<Mutex<VecDeque<(Instant, Vec<u8>)>>>;
+
+/// One end of an in-process link, for testing netplay on a single machine.
+pub struct LoopbackTransport {
+    outgoing: PacketQueue,
+    incoming: PacketQueue,
+    conditions: LinkConditions,
+    rng_state: u64,
+}
+
+impl LoopbackTransport {
+    /// Two connected ends; whatever one sends the other receives after `conditions` apply.
+    pub fn pair(conditions: LinkConditions) -> (Self, Self) {
+        let a_to_b = PacketQueue::default();
+        let b_to_a = PacketQueue::default();
+        (
+            Self {
+                outgoing: a_to_b.clone(),
+                incoming: b_to_a.clone(),
+                conditions,
+                rng_state: 0x9e3779b97f4a7c15,
+            },
+            Self {
+                outgoing: b_to_a,
+                incoming: a_to_b,
+                conditions,
+                rng_state: 0xd1b54a32d192ed03,
+            },
+        )
+    }
+
+    /// xorshift64*, mapped to 0.0..1.0.
+    fn next_random(&mut self) -> f32 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        let value = self.rng_state.wrapping_mul(0x2545f4914f6cdd1d);
+        (value >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, packet: &[u8]) {
+        if self.next_random() < self.conditions.packet_loss {
+            return;
+        }
+        let deliver_at = Instant::now() + self.conditions.delay;
+        if let Ok(mut queue) = self.outgoing.lock() {
+            queue.push_back((deliver_at, packet.to_vec()));
+        }
+    }
+
+    fn receive(&mut self) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        let Ok(mut queue) = self.incoming.lock() else {
+            return Vec::new();
+        };
+        let mut packets = Vec::new();
+        while queue
+            .front()
+            .is_some_and(|(deliver_at, _)| *deliver_at <= now)
+        {
+            if let Some((_, packet)) = queue.pop_front() {
+                packets.push(packet);
+            }
+        }
+        packets
+    }
+}
@@ -1,10 +1,20 @@
+use crate::character::CharacterStats;
+use crate::collision::{body_groups, world_groups, HurtboxShapes, Pushbox};
+use crate::game_state::GameState;
+use crate::hitstop::Hitstop;
+use crate::input_buffer::InputBuffer;
+use crate::motion::InputHistory;
+use crate::moves::Moveset;
+use crate::player_state::{CurrentMove, Guard, PlayerState, StateTimer};
+use crate::rollback::Rollback;
+use crate::simulation::GameSet;
 use crate::{movement, player};
 use bevy::prelude::*;
 use bevy_ecs_ldtk::prelude::*;
 use bevy_rapier2d::{
     control::KinematicCharacterController,
     dynamics::RigidBody,
-    geometry::{Collider, Friction},
+    geometry::{Collider, CollisionGroups, Friction},
 };
 use std::collections::{HashMap, HashSet};
 
@@ -14,6 +24,7 @@ impl Plugin for WorldPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins((LdtkPlugin,))
             .insert_resource(LevelSelection::Uid(0))
+            .init_resource::<LevelBounds>()
             .insert_resource(LdtkSettings {
                 level_spawn_behavior: LevelSpawnBehavior::UseWorldTranslation {
                     load_level_neighbors: true,
@@ -24,21 +35,49 @@ impl Plugin for WorldPlugin {
             .register_ldtk_int_cell::<WallBundle>(1)
             .register_ldtk_entity::<PlayerBundle>("Player")
             .add_systems(Startup, (setup,))
-            .add_systems(
-                Update,
-                (spawn_wall_collision, update_level_selection, restart_level),
-            );
+            .add_systems(OnEnter(GameState::Fight), spawn_world)
+            .add_systems(OnExit(GameState::MatchOver), despawn_world)
+            .add_systems(Update, (spawn_wall_collision, update_level_selection))
+            .add_systems(FixedUpdate, restart_level.in_set(GameSet::Player));
     }
 }
 
+pub const LDTK_PATH: &str = "tile-based-game.ldtk";
+
+/// Keeps the project loaded while no world is spawned, so menus can list its levels.
+#[derive(Resource)]
+pub struct LdtkProjectHandle(pub Handle<LdtkProject>);
+
 pub fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
-    let ldtk_handle = asset_server.load("tile-based-game.ldtk");
+    commands.insert_resource(LdtkProjectHandle(asset_server.load(LDTK_PATH)));
+}
+
+/// Spawns the level when a fight starts, unless it is still there from before a pause.
+pub fn spawn_world(
+    mut commands: Commands,
+    handle: Res<LdtkProjectHandle>,
+    world_query: Query<(), With<Handle<LdtkProject>>>,
+) {
+    if !world_query.is_empty() {
+        return;
+    }
     commands.spawn(LdtkWorldBundle {
-        ldtk_handle,
+        ldtk_handle: handle.0.clone(),
         ..Default::default()
     });
 }
 
+pub fn despawn_world(
+    mut commands: Commands,
+    mut level_bounds: ResMut<LevelBounds>,
+    world_query: Query<Entity, With<Handle<LdtkProject>>>,
+) {
+    level_bounds.0 = None;
+    for entity in world_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Component)]
 pub struct Wall;
 
@@ -53,14 +92,31 @@ pub struct PlayerBundle {
     pub sprite_bundle: SpriteBundle,
     pub player: player::Player,
     #[from_entity_instance]
+    pub player_id: player::PlayerId,
+    #[from_entity_instance]
     entity_instance: EntityInstance,
     velocity: movement::Velocity,
     controller: KinematicCharacterController,
     #[from_entity_instance]
     collider: ColliderBundle,
     attack_height: player::AttackHeight,
-    attack_cooldown: player::AttackCooldown,
     direction: player::Direction,
+    health: player::Health,
+    meter: player::Meter,
+    state: PlayerState,
+    state_timer: StateTimer,
+    current_move: CurrentMove,
+    guard: Guard,
+    hitstop: Hitstop,
+    pushbox: Pushbox,
+    hurtboxes: HurtboxShapes,
+    stats: CharacterStats,
+    moveset: Moveset,
+    input_state: movement::InputState,
+    input_history: InputHistory,
+    input_buffer: InputBuffer,
+    collision_groups: CollisionGroups,
+    rollback: Rollback,
 }
 
 impl Default for PlayerBundle {
@@ -70,23 +126,49 @@ impl Default for PlayerBundle {
                 ..Default::default()
             },
             player: player::Player,
+            player_id: Default::default(),
             entity_instance: Default::default(),
             velocity: Default::default(),
             controller: KinematicCharacterController {
                 autostep: None,
                 snap_to_ground: None,
                 custom_mass: Some(100.0),
+                // other players are kept apart by their pushboxes instead
+                filter_groups: Some(body_groups()),
 
                 ..Default::default()
             },
             collider: Default::default(),
             attack_height: player::AttackHeight::Normal,
-            attack_cooldown: player::AttackCooldown(Timer::from_seconds(0.5, TimerMode::Once)),
             direction: player::Direction::Left,
+            health: Default::default(),
+            meter: Default::default(),
+            state: Default::default(),
+            state_timer: Default::default(),
+            current_move: Default::default(),
+            guard: Default::default(),
+            hitstop: Default::default(),
+            pushbox: Default::default(),
+            hurtboxes: Default::default(),
+            stats: Default::default(),
+            moveset: Default::default(),
+            input_state: Default::default(),
+            input_history: Default::default(),
+            input_buffer: Default::default(),
+            collision_groups: body_groups(),
+            rollback: Rollback,
         }
     }
 }
 
+impl From<&EntityInstance> for player::PlayerId {
+    fn from(entity_instance: &EntityInstance) -> player::PlayerId {
+        // the LDtk "Slot" field is one-based so the level reads as P1/P2
+        let slot = entity_instance.get_int_field("Slot").copied().unwrap_or(1);
+        player::PlayerId((slot.max(1) - 1) as usize)
+    }
+}
+
 #[derive(Clone, Default, Bundle, LdtkIntCell)]
 pub struct ColliderBundle {
     pub collider: Collider,
@@ -96,8 +178,7 @@ impl From<&EntityInstance> for ColliderBundle {
     fn from(entity_instance: &EntityInstance) -> ColliderBundle {
         match entity_instance.identifier.as_ref() {
             "Player" => ColliderBundle {
-                collider: Collider::cuboid(14.0, 20.0),
-                ..Default::default()
+                collider: Collider::cuboid(player::PLAYER_HALF_SIZE.x, player::PLAYER_HALF_SIZE.y),
             },
             _ => ColliderBundle::default(),
         }
@@ -222,6 +303,7 @@ pub fn spawn_wall_collision(
                             ))
                             .insert(RigidBody::Fixed)
                             .insert(Friction::new(1.0))
+                            .insert(world_groups())
                             .insert(Transform::from_xyz(
                                 (wall_rect.left + wall_rect.right + 1) as f32 * grid_size as f32
                                     / 2.,
@@ -238,10 +320,15 @@ pub fn spawn_wall_collision(
     // state.set(state::AppState::Running);
 }
 
+/// World-space pixel bounds of the selected level, once it has spawned.
+#[derive(Resource, Default, Copy, Clone, Debug)]
+pub struct LevelBounds(pub Option<Rect>);
+
 pub fn update_level_selection(
     level_query: Query<(&LevelIid, &Transform), Without<player::Player>>,
-    player_query: Query<&Transform, With<player::Player>>,
+    player_query: Query<&GlobalTransform, With<player::Player>>,
     mut level_selection: ResMut<LevelSelection>,
+    mut current_bounds: ResMut<LevelBounds>,
     ldtk_projects: Query<&Handle<LdtkProject>>,
     ldtk_project_assets: Res<Assets<LdtkProject>>,
 ) {
@@ -262,26 +349,43 @@ pub fn update_level_selection(
             ),
         };
 
+        // players are children of the level, so only their global position is in world space
         for player_transform in &player_query {
-            if player_transform.translation.x < level_bounds.max.x
-                && player_transform.translation.x > level_bounds.min.x
-                && player_transform.translation.y < level_bounds.max.y
-                && player_transform.translation.y > level_bounds.min.y
+            let position = player_transform.translation();
+            if position.x < level_bounds.max.x
+                && position.x > level_bounds.min.x
+                && position.y < level_bounds.max.y
+                && position.y > level_bounds.min.y
                 && !level_selection.is_match(&LevelIndices::default(), level)
             {
                 *level_selection = LevelSelection::iid(level.iid.clone());
             }
         }
+
+        if level_selection.is_match(&LevelIndices::default(), level) {
+            current_bounds.0 = Some(level_bounds);
+        }
     }
 }
 
+/// Iid of the level `selection` points at, which other machines can load by.
+pub fn selected_level_iid(selection: &LevelSelection, project: &LdtkProject) -> Option<String> {
+    project
+        .iter_raw_levels()
+        .enumerate()
+        .find(|(index, level)| selection.is_match(&LevelIndices::in_root(*index), level))
+        .map(|(_, level)| level.iid.clone())
+}
+
+/// Respawns the level once per press of the reset action. The respawned players carry on
+/// from their slot's `LastInput`, so holding the action does not restart the level again.
 pub fn restart_level(
     mut commands: Commands,
     level_query: Query<Entity, With<LevelIid>>,
-    mut input: EventReader<movement::PlayerInputEvent>,
+    player_query: Query<&movement::InputState, With<player::Player>>,
 ) {
-    for event in input.read() {
-        if event.0.contains(&movement::PlayerInput::ResetLevel) {
+    for input in player_query.iter() {
+        if input.just_pressed(movement::PlayerInput::ResetLevel) {
             for level_entity in &level_query {
                 commands.entity(level_entity).insert(Respawn);
             }